@@ -8,6 +8,8 @@ use std::sync::atomic::{AtomicU32, Ordering};
 
 use serde::{Deserialize, Serialize};
 
+pub mod ws;
+
 /// Protocol buffer and gRPC definitions, automatically generated by Tonic.
 #[allow(missing_docs, non_snake_case)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -72,6 +74,12 @@ impl IdCounter {
         Sid(self.next_sid.fetch_add(1, Ordering::Relaxed))
     }
 
+    /// Mark a shell ID chosen elsewhere as used, so that `next_sid` never
+    /// returns it.
+    pub fn reserve_sid(&self, id: Sid) {
+        self.next_sid.fetch_max(id.0 + 1, Ordering::Relaxed);
+    }
+
     /// Returns the next unique user ID.
     pub fn next_uid(&self) -> Uid {
         Uid(self.next_uid.fetch_add(1, Ordering::Relaxed))
@@ -0,0 +1,209 @@
+//! Serializable types sent and received over WebSocket by web clients.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{exit_status::Status, Compression, ExitStatus};
+use crate::{Sid, Uid};
+
+/// Real-time message conveying the position and size of a terminal.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsWinsize {
+    /// The top-left x-coordinate of the window, offset from origin.
+    pub x: i32,
+    /// The top-left y-coordinate of the window, offset from origin.
+    pub y: i32,
+    /// The number of rows in the window.
+    pub rows: u16,
+    /// The number of columns in the terminal.
+    pub cols: u16,
+}
+
+impl Default for WsWinsize {
+    fn default() -> Self {
+        WsWinsize {
+            x: 0,
+            y: 0,
+            rows: 24,
+            cols: 80,
+        }
+    }
+}
+
+/// Real-time message providing information about a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsUser {
+    /// The user's display name.
+    pub name: String,
+    /// Live coordinates of the mouse cursor, if available.
+    pub cursor: Option<(i32, i32)>,
+    /// Currently focused terminal window ID.
+    pub focus: Option<Sid>,
+    /// Whether the user has write permissions in the session.
+    pub can_write: bool,
+}
+
+/// How the process in a shell ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum WsExitStatus {
+    /// The process exited with this code.
+    Code(i32),
+    /// The process was terminated by the signal with this number.
+    Signal(i32),
+}
+
+impl From<WsExitStatus> for ExitStatus {
+    fn from(status: WsExitStatus) -> Self {
+        let status = match status {
+            WsExitStatus::Code(code) => Status::Code(code),
+            WsExitStatus::Signal(signal) => Status::Signal(signal),
+        };
+        ExitStatus {
+            status: Some(status),
+        }
+    }
+}
+
+impl TryFrom<ExitStatus> for WsExitStatus {
+    type Error = &'static str;
+
+    fn try_from(status: ExitStatus) -> Result<Self, Self::Error> {
+        match status.status {
+            Some(Status::Code(code)) => Ok(WsExitStatus::Code(code)),
+            Some(Status::Signal(signal)) => Ok(WsExitStatus::Signal(signal)),
+            None => Err("exit status is missing"),
+        }
+    }
+}
+
+/// Chunk of encrypted terminal output, which may have been compressed before
+/// it was encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum WsChunk {
+    /// Data encrypted in the output stream, at its sequence number.
+    Raw(Bytes),
+    /// Data compressed with raw deflate, then encrypted in the compressed
+    /// output stream.
+    Deflate {
+        /// Encrypted, compressed data.
+        data: Bytes,
+        /// Length of the data after decompression.
+        size: u64,
+        /// Offset of the data in the compressed output stream.
+        offset: u64,
+    },
+}
+
+impl WsChunk {
+    /// Construct a chunk from the fields of a protobuf message.
+    pub fn from_parts(
+        data: Bytes,
+        compression: i32,
+        size: u64,
+        offset: u64,
+    ) -> Result<Self, &'static str> {
+        match Compression::try_from(compression) {
+            Ok(Compression::None) => Ok(WsChunk::Raw(data)),
+            Ok(Compression::Deflate) => Ok(WsChunk::Deflate { data, size, offset }),
+            Err(_) => Err("unknown compression of terminal data"),
+        }
+    }
+
+    /// Split the chunk into fields of a protobuf message, in the same order as
+    /// [`WsChunk::from_parts`].
+    pub fn into_parts(self) -> (Bytes, i32, u64, u64) {
+        match self {
+            WsChunk::Raw(data) => (data, Compression::None.into(), 0, 0),
+            WsChunk::Deflate { data, size, offset } => {
+                (data, Compression::Deflate.into(), size, offset)
+            }
+        }
+    }
+
+    /// Encrypted data in the chunk, as it is stored.
+    pub fn data(&self) -> &Bytes {
+        match self {
+            WsChunk::Raw(data) | WsChunk::Deflate { data, .. } => data,
+        }
+    }
+
+    /// Length of the terminal output in the chunk, which is how far it
+    /// advances the sequence number.
+    pub fn size(&self) -> u64 {
+        match self {
+            WsChunk::Raw(data) => data.len() as u64,
+            WsChunk::Deflate { size, .. } => *size,
+        }
+    }
+}
+
+/// A real-time message sent from the server over WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum WsServer {
+    /// Initial server message, with the user's ID and session metadata.
+    Hello(Uid, String),
+    /// The user's authentication was invalid.
+    InvalidAuth(),
+    /// The user is waiting for the host to let them into the session.
+    WaitingApproval(),
+    /// The host did not let the user into the session.
+    JoinDenied(),
+    /// The host removed the user from the session.
+    Kicked(),
+    /// A snapshot of all current users in the session.
+    Users(Vec<(Uid, WsUser)>),
+    /// Info about a single user in the session: joined, left, or changed.
+    UserDiff(Uid, Option<WsUser>),
+    /// Notification when the set of open shells has changed.
+    Shells(Vec<(Sid, WsWinsize)>),
+    /// The process of a shell has ended, but its output is kept until a user
+    /// closes it.
+    ShellExited(Sid, WsExitStatus),
+    /// Encrypted title, working directory and process of a shell, with the
+    /// offset of the data in its info stream.
+    ShellInfo(Sid, u64, Bytes),
+    /// Subscription results, in the form of terminal data chunks.
+    Chunks(Sid, u64, Vec<WsChunk>),
+    /// Get a chat message tuple `(uid, name, text)` from the room.
+    Hear(Uid, String, String),
+    /// Forward a latency measurement between the server and backend shell.
+    ShellLatency(u64),
+    /// Echo back a timestamp, for the the client's own latency measurement.
+    Pong(u64),
+    /// Alert the client of an application error.
+    Error(String),
+}
+
+/// A real-time message sent from the client over WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum WsClient {
+    /// Authenticate the user's encryption key by zeros block and write password
+    /// (if provided).
+    Authenticate(Bytes, Option<Bytes>),
+    /// Set the name of the current user.
+    SetName(String),
+    /// Send real-time information about the user's cursor.
+    SetCursor(Option<(i32, i32)>),
+    /// Set the currently focused shell.
+    SetFocus(Option<Sid>),
+    /// Create a new shell.
+    Create(i32, i32),
+    /// Close a specific shell.
+    Close(Sid),
+    /// Move a shell window to a new position and focus it.
+    Move(Sid, Option<WsWinsize>),
+    /// Add user data to a given shell.
+    Data(Sid, Bytes, u64),
+    /// Subscribe to a shell, starting at a given chunk index.
+    Subscribe(Sid, u64),
+    /// Send a a chat message to the room.
+    Chat(String),
+    /// Send a ping to the server, for latency measurement.
+    Ping(u64),
+}
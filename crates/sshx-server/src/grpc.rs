@@ -7,16 +7,19 @@ use base64::prelude::{Engine as _, BASE64_STANDARD};
 use hmac::Mac;
 use sshx_core::proto::{
     client_update::ClientMessage, server_update::ServerMessage, sshx_service_server::SshxService,
-    ClientUpdate, CloseRequest, CloseResponse, OpenRequest, OpenResponse, ServerUpdate,
+    ClientUpdate, CloseRequest, CloseResponse, Compression, NewShell, OpenRequest, OpenResponse,
+    ServerUpdate,
 };
-use sshx_core::{rand_alphanumeric, Sid};
+use sshx_core::{rand_alphanumeric, Sid, Uid};
 use tokio::sync::mpsc;
 use tokio::time::{self, MissedTickBehavior};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 use tracing::{error, info, warn};
 
+use crate::listen::ClientCert;
 use crate::session::{Metadata, Session};
+use crate::web::protocol::{WsChunk, WsExitStatus, WsServer};
 use crate::ServerState;
 
 /// Interval for synchronizing sequence numbers with the client.
@@ -25,6 +28,10 @@ pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
 /// Interval for measuring client latency.
 pub const PING_INTERVAL: Duration = Duration::from_secs(2);
 
+/// Compression of terminal data that web clients can read, which is limited to
+/// the formats of the browser's `DecompressionStream`.
+const SUPPORTED_COMPRESSION: [Compression; 1] = [Compression::Deflate];
+
 /// Server that handles gRPC requests from the sshx command-line client.
 #[derive(Clone)]
 pub struct GrpcServer(Arc<ServerState>);
@@ -43,7 +50,17 @@ impl SshxService for GrpcServer {
     type ChannelStream = ReceiverStream<Result<ServerUpdate, Status>>;
 
     async fn open(&self, request: Request<OpenRequest>) -> RR<OpenResponse> {
+        if self.0.require_client_cert() && request.extensions().get::<ClientCert>().is_none() {
+            return Err(Status::unauthenticated("a client certificate is required"));
+        }
         let request = request.into_inner();
+
+        // Pick the client's preferred compression that web clients can read.
+        let compression = request
+            .compression()
+            .find(|compression| SUPPORTED_COMPRESSION.contains(compression))
+            .unwrap_or(Compression::None);
+
         let origin = self.0.override_origin().unwrap_or(request.origin);
         if origin.is_empty() {
             return Err(Status::invalid_argument("origin is empty"));
@@ -58,6 +75,7 @@ impl SshxService for GrpcServer {
                     encrypted_zeros: request.encrypted_zeros,
                     name: request.name,
                     write_password_hash: request.write_password_hash,
+                    require_approval: request.require_approval,
                 };
                 self.0.insert(&name, Arc::new(Session::new(metadata)));
             }
@@ -68,6 +86,7 @@ impl SshxService for GrpcServer {
             name,
             token: BASE64_STANDARD.encode(token.into_bytes()),
             url,
+            compression: compression.into(),
         }))
     }
 
@@ -146,6 +165,13 @@ async fn handle_streaming(
     let mut ping_interval = time::interval(PING_INTERVAL);
     ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
+    // Keep the client up to date with the users in the session.
+    let mut broadcast_stream = session.subscribe_broadcast();
+    let mut users_sent = session.user_list();
+    if !send_msg(tx, ServerMessage::Users(users_sent.clone())).await {
+        return Err("failed to send user list");
+    }
+
     loop {
         tokio::select! {
             // Send periodic sync messages to the client.
@@ -159,6 +185,20 @@ async fn handle_streaming(
             _ = ping_interval.tick() => {
                 send_msg(tx, ServerMessage::Ping(get_time_ms())).await;
             }
+            // Send the user list again whenever a user joins, leaves or changes
+            // their name or permissions. Cursor and focus moves are not sent.
+            Some(result) = broadcast_stream.next() => {
+                // Lagging behind could mean that we missed a change.
+                if matches!(result, Ok(WsServer::UserDiff(..)) | Err(_)) {
+                    let users = session.user_list();
+                    if users != users_sent {
+                        if !send_msg(tx, ServerMessage::Users(users.clone())).await {
+                            return Err("failed to send user list");
+                        }
+                        users_sent = users;
+                    }
+                }
+            }
             // Send buffered server updates to the client.
             Ok(msg) = session.update_rx().recv() => {
                 if !send_msg(tx, msg).await {
@@ -194,7 +234,12 @@ async fn handle_update(tx: &ServerTx, session: &Session, update: ClientUpdate) -
             return send_err(tx, "unexpected hello".into()).await;
         }
         Some(ClientMessage::Data(data)) => {
-            if let Err(err) = session.add_data(Sid(data.id), data.data, data.seq) {
+            let chunk =
+                match WsChunk::from_parts(data.data, data.compression, data.size, data.offset) {
+                    Ok(chunk) => chunk,
+                    Err(err) => return send_err(tx, format!("add data: {err}")).await,
+                };
+            if let Err(err) = session.add_data(Sid(data.id), chunk, data.seq) {
                 return send_err(tx, format!("add data: {:?}", err)).await;
             }
         }
@@ -210,6 +255,46 @@ async fn handle_update(tx: &ServerTx, session: &Session, update: ClientUpdate) -
                 return send_err(tx, format!("close shell: {:?}", err)).await;
             }
         }
+        Some(ClientMessage::ExitedShell(exited)) => {
+            let id = Sid(exited.id);
+            let result = match exited.status.map(WsExitStatus::try_from) {
+                Some(Ok(status)) => session.exit_shell(id, status),
+                _ => session.close_shell(id),
+            };
+            if let Err(err) = result {
+                return send_err(tx, format!("exited shell: {:?}", err)).await;
+            }
+        }
+        Some(ClientMessage::ShellInfo(info)) => {
+            if let Err(err) = session.set_shell_info(Sid(info.id), info.offset, info.data) {
+                return send_err(tx, format!("shell info: {:?}", err)).await;
+            }
+        }
+        Some(ClientMessage::JoinResponse(response)) => {
+            session.answer_join(Uid(response.user_id), response.approved);
+        }
+        Some(ClientMessage::KickUser(id)) => {
+            if let Err(err) = session.kick_user(Uid(id)) {
+                return send_err(tx, format!("kick user: {:?}", err)).await;
+            }
+        }
+        Some(ClientMessage::SetReadOnly(read_only)) => {
+            session.set_read_only(read_only);
+            session.sync_now();
+        }
+        Some(ClientMessage::CreateShell(position)) => {
+            let id = session.counter().next_sid();
+            session.sync_now();
+            let new_shell = NewShell {
+                id: id.0,
+                x: position.x,
+                y: position.y,
+            };
+            return send_msg(tx, ServerMessage::CreateShell(new_shell)).await;
+        }
+        Some(ClientMessage::CloseShell(id)) => {
+            return send_msg(tx, ServerMessage::CloseShell(id)).await;
+        }
         Some(ClientMessage::Pong(ts)) => {
             let latency = get_time_ms().saturating_sub(ts);
             session.send_latency_measurement(latency);
@@ -12,10 +12,11 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
-use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+use std::{fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::serve::{Listener, ListenerExt};
+use rustls::ServerConfig;
 use tokio::net::TcpListener;
 use tracing::debug;
 use utils::Shutdown;
@@ -44,19 +45,34 @@ pub struct ServerOptions {
 
     /// Hostname of this server, if running multiple servers.
     pub host: Option<String>,
+
+    /// PEM file with the TLS certificate chain to serve HTTPS with.
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate.
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM file with CA certificates that sign client certificates.
+    ///
+    /// If set, sshx clients must present a certificate signed by one of these
+    /// CAs to open a session. Web clients are not affected.
+    pub client_ca: Option<PathBuf>,
 }
 
 /// Stateful object that manages the sshx server, with graceful termination.
 pub struct Server {
     state: Arc<ServerState>,
+    tls_config: Option<Arc<ServerConfig>>,
     shutdown: Shutdown,
 }
 
 impl Server {
     /// Create a new application server, but do not listen for connections yet.
     pub fn new(options: ServerOptions) -> Result<Self> {
+        let tls_config = listen::tls_config(&options)?.map(Arc::new);
         Ok(Self {
             state: Arc::new(ServerState::new(options)?),
+            tls_config,
             shutdown: Shutdown::new(),
         })
     }
@@ -88,12 +104,28 @@ impl Server {
         listen::start_server(self.state(), listener, self.shutdown.wait()).await
     }
 
+    /// Run the application server over TLS, using the certificate from the
+    /// server options.
+    pub async fn listen_tls(&self, listener: TcpListener) -> Result<()> {
+        let tls_config = self
+            .tls_config
+            .clone()
+            .context("no TLS certificate was configured")?;
+        self.listen(listen::TlsListener::new(listener, tls_config)?)
+            .await
+    }
+
     /// Convenience function to call [`Server::listen`] bound to a TCP address.
     ///
     /// This also sets `TCP_NODELAY` on the incoming connections for performance
-    /// reasons, as a reasonable default.
+    /// reasons, as a reasonable default. Connections use TLS if a certificate
+    /// was configured.
     pub async fn bind(&self, addr: &SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?.tap_io(|tcp_stream| {
+        let listener = TcpListener::bind(addr).await?;
+        if self.tls_config.is_some() {
+            return self.listen_tls(listener).await;
+        }
+        let listener = listener.tap_io(|tcp_stream| {
             if let Err(err) = tcp_stream.set_nodelay(true) {
                 debug!("failed to set TCP_NODELAY on incoming connection: {err:#}");
             }
@@ -1,15 +1,37 @@
-use std::{fmt::Debug, future::Future, sync::Arc};
+use std::any::Any;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use std::{fmt::Debug, future::Future, io, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
 use axum::body::Body;
-use axum::serve::Listener;
+use axum::serve::{IncomingStream, Listener};
 use http::{header::CONTENT_TYPE, Request};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
 use sshx_core::proto::{sshx_service_server::SshxServiceServer, FILE_DESCRIPTOR_SET};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 use tonic::service::Routes as TonicRoutes;
-use tower::{make::Shared, steer::Steer, ServiceExt};
+use tower::{steer::Steer, ServiceExt};
 use tower_http::trace::TraceLayer;
+use tracing::{debug, error};
 
-use crate::{grpc::GrpcServer, web, ServerState};
+use crate::{grpc::GrpcServer, web, ServerOptions, ServerState};
+
+/// Timeout for clients to complete a TLS handshake after connecting.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request extension marking connections with a verified client certificate.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientCert;
 
 /// Bind and listen from the application, with a state and termination signal.
 ///
@@ -55,7 +77,21 @@ where
             }
         },
     );
-    let make_svc = Shared::new(svc);
+    let make_svc = tower::service_fn(move |incoming: IncomingStream<'_, L>| {
+        // Certificates are verified during the handshake, so any that are
+        // present on a connection are signed by the client CA.
+        let io: &dyn Any = incoming.io();
+        let client_cert = io
+            .downcast_ref::<TlsStream<TcpStream>>()
+            .is_some_and(|stream| stream.get_ref().1.peer_certificates().is_some());
+        let svc = svc.clone().map_request(move |mut req: Request<Body>| {
+            if client_cert {
+                req.extensions_mut().insert(ClientCert);
+            }
+            req
+        });
+        async move { Ok::<_, Infallible>(svc) }
+    });
 
     axum::serve(listener, make_svc)
         .with_graceful_shutdown(signal)
@@ -63,3 +99,110 @@ where
 
     Ok(())
 }
+
+/// Build the TLS configuration from the server options, if enabled.
+pub(crate) fn tls_config(options: &ServerOptions) -> Result<Option<ServerConfig>> {
+    let (cert_path, key_path) = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => {
+            ensure!(
+                options.client_ca.is_none(),
+                "client certificates can only be verified over TLS"
+            );
+            return Ok(None);
+        }
+        _ => bail!("a TLS certificate and its key must be given together"),
+    };
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &options.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots.add(cert).context("invalid client CA certificate")?;
+            }
+            // Browsers do not present certificates, so they are only checked
+            // when opening sessions.
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("failed to read key {}", key_path.display()))?;
+    let mut config = builder.with_single_cert(read_certs(cert_path)?, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Some(config))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates from {}", path.display()))?;
+    ensure!(!certs.is_empty(), "no certificates in {}", path.display());
+    Ok(certs)
+}
+
+/// Listener that accepts TLS connections, doing handshakes in the background.
+pub(crate) struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub(crate) fn new(listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (tx, accepted) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    _ = tx.closed() => break,
+                    result = listener.accept() => match result {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            error!(?err, "failed to accept connection");
+                            time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    },
+                };
+                if let Err(err) = stream.set_nodelay(true) {
+                    debug!("failed to set TCP_NODELAY on incoming connection: {err:#}");
+                }
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => _ = tx.send((stream, addr)).await,
+                        Ok(Err(err)) => debug!(%addr, "TLS handshake failed: {err}"),
+                        Err(_) => debug!(%addr, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            accepted,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Addr = SocketAddr;
+    type Io = TlsStream<TcpStream>;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(conn) => conn,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     process::ExitCode,
 };
 
@@ -36,6 +37,19 @@ struct Args {
     /// Hostname of this server, if running multiple servers.
     #[clap(long)]
     host: Option<String>,
+
+    /// PEM file with a TLS certificate chain, to serve HTTPS directly.
+    #[clap(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate.
+    #[clap(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file with CA certificates for client certificates. When set, sshx
+    /// clients must present a certificate signed by this CA to share.
+    #[clap(long, value_name = "PATH", requires = "tls_cert")]
+    client_ca: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -50,6 +64,9 @@ async fn start(args: Args) -> Result<()> {
     options.override_origin = args.override_origin;
     options.redis_url = args.redis_url;
     options.host = args.host;
+    options.tls_cert = args.tls_cert;
+    options.tls_key = args.tls_key;
+    options.client_ca = args.client_ca;
 
     let server = Server::new(options)?;
 
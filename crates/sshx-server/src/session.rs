@@ -1,24 +1,25 @@
 //! Core logic for sshx sessions, independent of message transport.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use sshx_core::{
-    proto::{server_update::ServerMessage, SequenceNumbers},
+    proto::{server_update::ServerMessage, JoinRequest, SequenceNumbers, UserInfo, UserList},
     IdCounter, Sid, Uid,
 };
-use tokio::sync::{broadcast, watch, Notify};
+use tokio::sync::{broadcast, oneshot, watch, Notify};
 use tokio::time::Instant;
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream};
 use tokio_stream::Stream;
 use tracing::{debug, warn};
 
 use crate::utils::Shutdown;
-use crate::web::protocol::{WsServer, WsUser, WsWinsize};
+use crate::web::protocol::{WsChunk, WsExitStatus, WsServer, WsUser, WsWinsize};
 
 mod snapshot;
 
@@ -36,6 +37,9 @@ pub struct Metadata {
 
     /// Password for write access to the session.
     pub write_password_hash: Option<Bytes>,
+
+    /// Whether new users must be approved by the host before joining.
+    pub require_approval: bool,
 }
 
 /// In-memory state for a single sshx session.
@@ -50,6 +54,19 @@ pub struct Session {
     /// Metadata for currently connected users.
     users: RwLock<HashMap<Uid, WsUser>>,
 
+    /// Users waiting for the host to let them in, with where to send the
+    /// answer.
+    pending_joins: Mutex<HashMap<Uid, oneshot::Sender<bool>>>,
+
+    /// Users who were given write access when they joined.
+    writers: Mutex<HashSet<Uid>>,
+
+    /// Set when the host has stopped all users from typing.
+    read_only: AtomicBool,
+
+    /// Broadcasts the IDs of users that the host has removed.
+    kick_tx: broadcast::Sender<Uid>,
+
     /// Atomic counter to get new, unique IDs.
     counter: IdCounter,
 
@@ -86,17 +103,27 @@ struct State {
     seqnum: u64,
 
     /// Terminal data chunks.
-    data: Vec<Bytes>,
+    data: Vec<WsChunk>,
 
     /// Number of pruned data chunks before `data[0]`.
     chunk_offset: u64,
 
-    /// Number of bytes in pruned data chunks.
+    /// Number of bytes of output in pruned data chunks.
     byte_offset: u64,
 
+    /// Number of bytes stored in data chunks, which is less than the length of
+    /// their output if they are compressed.
+    stored_bytes: u64,
+
     /// Set when this shell is terminated.
     closed: bool,
 
+    /// How the shell's process ended, if it has.
+    exit: Option<WsExitStatus>,
+
+    /// Latest encrypted shell info, with its stream offset.
+    info: Option<(u64, Bytes)>,
+
     /// Updated when any of the above fields change.
     notify: Arc<Notify>,
 }
@@ -110,6 +137,10 @@ impl Session {
             metadata,
             shells: RwLock::new(HashMap::new()),
             users: RwLock::new(HashMap::new()),
+            pending_joins: Mutex::new(HashMap::new()),
+            writers: Mutex::new(HashSet::new()),
+            read_only: AtomicBool::new(false),
+            kick_tx: broadcast::channel(16).0,
             counter: IdCounter::default(),
             last_accessed: Mutex::new(now),
             source: watch::channel(Vec::new()).0,
@@ -136,7 +167,8 @@ impl Session {
         let shells = self.shells.read();
         let mut map = HashMap::with_capacity(shells.len());
         for (key, value) in &*shells {
-            if !value.closed {
+            // Exited shells have no process on the client to sync with.
+            if !value.closed && value.exit.is_none() {
                 map.insert(key.0, value.seqnum);
             }
         }
@@ -160,7 +192,7 @@ impl Session {
         &self,
         id: Sid,
         mut chunknum: u64,
-    ) -> impl Stream<Item = (u64, Vec<Bytes>)> + '_ {
+    ) -> impl Stream<Item = (u64, Vec<WsChunk>)> + '_ {
         async_stream::stream! {
             while !self.shutdown.is_terminated() {
                 // We absolutely cannot hold `shells` across an await point,
@@ -178,7 +210,7 @@ impl Session {
                     let current_chunks = shell.chunk_offset + shell.data.len() as u64;
                     if chunknum < current_chunks {
                         let start = chunknum.saturating_sub(shell.chunk_offset) as usize;
-                        seqnum += shell.data[..start].iter().map(|x| x.len() as u64).sum::<u64>();
+                        seqnum += shell.data[..start].iter().map(WsChunk::size).sum::<u64>();
                         chunks = shell.data[start..].to_vec();
                         chunknum = current_chunks;
                     }
@@ -203,6 +235,8 @@ impl Session {
             Occupied(_) => bail!("shell already exists with id={id}"),
             Vacant(v) => v.insert(State::default()),
         };
+        // The client may have chosen the ID, so users never reuse it.
+        self.counter.reserve_sid(id);
         self.source.send_modify(|source| {
             let winsize = WsWinsize {
                 x: center.0,
@@ -232,6 +266,55 @@ impl Session {
         Ok(())
     }
 
+    /// Record that the process of a shell has ended, keeping the shell and
+    /// its output until a user closes it.
+    pub fn exit_shell(&self, id: Sid, status: WsExitStatus) -> Result<()> {
+        self.get_shell_mut(id)?.exit = Some(status);
+        self.broadcast.send(WsServer::ShellExited(id, status)).ok();
+        self.sync_now();
+        Ok(())
+    }
+
+    /// Store the latest info about what is running in a shell.
+    pub fn set_shell_info(&self, id: Sid, offset: u64, data: Bytes) -> Result<()> {
+        let mut shell = self.get_shell_mut(id)?;
+        shell.info = Some((offset, data.clone()));
+        drop(shell);
+        self.broadcast
+            .send(WsServer::ShellInfo(id, offset, data))
+            .ok();
+        Ok(())
+    }
+
+    /// List the latest info of every open shell that has any.
+    pub fn shell_infos(&self) -> Vec<(Sid, u64, Bytes)> {
+        let shells = self.shells.read();
+        let infos = shells.iter().filter(|(_, shell)| !shell.closed);
+        infos
+            .filter_map(|(id, shell)| {
+                let (offset, data) = shell.info.clone()?;
+                Some((*id, offset, data))
+            })
+            .collect()
+    }
+
+    /// List the open shells whose process has ended, with how it ended.
+    pub fn exited_shells(&self) -> Vec<(Sid, WsExitStatus)> {
+        let shells = self.shells.read();
+        let exited = shells.iter().filter(|(_, shell)| !shell.closed);
+        exited
+            .filter_map(|(id, shell)| Some((*id, shell.exit?)))
+            .collect()
+    }
+
+    /// Check if the process of a shell has ended.
+    pub fn has_exited(&self, id: Sid) -> bool {
+        self.shells
+            .read()
+            .get(&id)
+            .is_some_and(|shell| shell.exit.is_some())
+    }
+
     fn get_shell_mut(&self, id: Sid) -> Result<impl DerefMut<Target = State> + '_> {
         let shells = self.shells.write();
         match shells.get(&id) {
@@ -256,25 +339,28 @@ impl Session {
     }
 
     /// Receive new data into the session.
-    pub fn add_data(&self, id: Sid, data: Bytes, seq: u64) -> Result<()> {
+    pub fn add_data(&self, id: Sid, chunk: WsChunk, seq: u64) -> Result<()> {
         let mut shell = self.get_shell_mut(id)?;
 
-        if seq <= shell.seqnum && seq + data.len() as u64 > shell.seqnum {
-            let start = shell.seqnum - seq;
-            let segment = data.slice(start as usize..);
-            debug!(%id, bytes = segment.len(), "adding data to shell");
-            shell.seqnum += segment.len() as u64;
+        if seq <= shell.seqnum && seq + chunk.size() > shell.seqnum {
+            let segment = match chunk {
+                WsChunk::Raw(data) => WsChunk::Raw(data.slice((shell.seqnum - seq) as usize..)),
+                // Compressed chunks cannot be split, so they must not overlap.
+                chunk if seq == shell.seqnum => chunk,
+                _ => return Ok(()),
+            };
+            debug!(%id, bytes = segment.data().len(), "adding data to shell");
+            shell.seqnum += segment.size();
+            shell.stored_bytes += segment.data().len() as u64;
             shell.data.push(segment);
 
             // Prune old chunks if we've exceeded the maximum stored bytes.
-            let mut stored_bytes = shell.seqnum - shell.byte_offset;
-            if stored_bytes > SHELL_STORED_BYTES {
+            if shell.stored_bytes > SHELL_STORED_BYTES {
                 let mut offset = 0;
-                while offset < shell.data.len() && stored_bytes > SHELL_STORED_BYTES {
-                    let bytes = shell.data[offset].len() as u64;
-                    stored_bytes -= bytes;
+                while offset < shell.data.len() && shell.stored_bytes > SHELL_STORED_BYTES {
+                    shell.stored_bytes -= shell.data[offset].data().len() as u64;
                     shell.chunk_offset += 1;
-                    shell.byte_offset += bytes;
+                    shell.byte_offset += shell.data[offset].size();
                     offset += 1;
                 }
                 shell.data.drain(..offset);
@@ -295,6 +381,11 @@ impl Session {
             .collect()
     }
 
+    /// Get a copy of a user by ID, if they are in the session.
+    pub fn get_user(&self, id: Uid) -> Option<WsUser> {
+        self.users.read().get(&id).cloned()
+    }
+
     /// Update a user in place by ID, applying a callback to the object.
     pub fn update_user(&self, id: Uid, f: impl FnOnce(&mut WsUser)) -> Result<()> {
         let updated_user = {
@@ -324,11 +415,14 @@ impl Session {
         match self.users.write().entry(id) {
             Occupied(_) => bail!("user already exists with id={id}"),
             Vacant(v) => {
+                if can_write {
+                    self.writers.lock().insert(id);
+                }
                 let user = WsUser {
                     name: format!("User {id}"),
                     cursor: None,
                     focus: None,
-                    can_write,
+                    can_write: can_write && !self.read_only(),
                 };
                 v.insert(user.clone());
                 self.broadcast.send(WsServer::UserDiff(id, Some(user))).ok();
@@ -342,9 +436,96 @@ impl Session {
         if self.users.write().remove(&id).is_none() {
             warn!(%id, "invariant violation: removed user that does not exist");
         }
+        self.writers.lock().remove(&id);
         self.broadcast.send(WsServer::UserDiff(id, None)).ok();
     }
 
+    /// Returns the users in the session, in the form sent to the backend
+    /// client.
+    pub fn user_list(&self) -> UserList {
+        let mut users: Vec<_> = self
+            .users
+            .read()
+            .iter()
+            .map(|(id, user)| UserInfo {
+                id: id.0,
+                name: user.name.clone(),
+                can_write: user.can_write,
+            })
+            .collect();
+        users.sort_by_key(|user| user.id);
+        UserList {
+            users,
+            read_only: self.read_only(),
+        }
+    }
+
+    /// Disconnect a user from the session.
+    pub fn kick_user(&self, id: Uid) -> Result<()> {
+        if !self.users.read().contains_key(&id) {
+            bail!("user not found");
+        }
+        self.kick_tx.send(id).ok();
+        Ok(())
+    }
+
+    /// Receive the IDs of users that should be disconnected.
+    pub fn subscribe_kicks(&self) -> broadcast::Receiver<Uid> {
+        self.kick_tx.subscribe()
+    }
+
+    /// Returns whether the host has stopped all users from typing.
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Stop all users from typing into shells, or give write access back to
+    /// the users who had it before.
+    pub fn set_read_only(&self, read_only: bool) {
+        let mut users = self.users.write();
+        self.read_only.store(read_only, Ordering::Relaxed);
+        let writers = self.writers.lock();
+        for (id, user) in users.iter_mut() {
+            let can_write = writers.contains(id) && !read_only;
+            if user.can_write != can_write {
+                user.can_write = can_write;
+                self.broadcast
+                    .send(WsServer::UserDiff(*id, Some(user.clone())))
+                    .ok();
+            }
+        }
+    }
+
+    /// Ask the host to let a user into the session.
+    ///
+    /// Returns a receiver for the host's answer, which is dropped if the
+    /// request is cancelled with [`Session::cancel_join`].
+    pub async fn request_join(&self, id: Uid, name: &str) -> Result<oneshot::Receiver<bool>> {
+        let (tx, rx) = oneshot::channel();
+        self.pending_joins.lock().insert(id, tx);
+        let request = JoinRequest {
+            user_id: id.0,
+            name: name.into(),
+        };
+        self.update_tx
+            .send(ServerMessage::JoinRequest(request))
+            .await?;
+        Ok(rx)
+    }
+
+    /// Pass on the host's answer to a user waiting to join.
+    pub fn answer_join(&self, id: Uid, approved: bool) {
+        match self.pending_joins.lock().remove(&id) {
+            Some(tx) => _ = tx.send(approved),
+            None => debug!(%id, "answer for a user that is no longer waiting"),
+        }
+    }
+
+    /// Stop waiting for an answer about a user, such as when they leave.
+    pub fn cancel_join(&self, id: Uid) {
+        self.pending_joins.lock().remove(&id);
+    }
+
     /// Check if a user has write permission in the session.
     pub fn check_write_permission(&self, user_id: Uid) -> Result<()> {
         let users = self.users.read();
@@ -2,15 +2,15 @@
 
 use std::collections::BTreeMap;
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{ensure, Context, Error, Result};
 use prost::Message;
 use sshx_core::{
-    proto::{SerializedSession, SerializedShell},
+    proto::{SerializedChunk, SerializedSession, SerializedShell},
     Sid, Uid,
 };
 
 use super::{Metadata, Session, State};
-use crate::web::protocol::WsWinsize;
+use crate::web::protocol::{WsChunk, WsWinsize};
 
 /// Persist at most this many bytes of output in storage, per shell.
 const SHELL_SNAPSHOT_BYTES: u64 = 1 << 15; // 32 KiB
@@ -33,21 +33,34 @@ impl Session {
                     let mut prefix = 0;
                     let mut chunk_offset = shell.chunk_offset;
                     let mut byte_offset = shell.byte_offset;
+                    let mut stored_bytes = shell.stored_bytes;
 
                     for i in 0..shell.data.len() {
-                        if shell.seqnum - byte_offset > SHELL_SNAPSHOT_BYTES {
+                        if stored_bytes > SHELL_SNAPSHOT_BYTES {
                             prefix += 1;
                             chunk_offset += 1;
-                            byte_offset += shell.data[i].len() as u64;
+                            byte_offset += shell.data[i].size();
+                            stored_bytes -= shell.data[i].data().len() as u64;
                         } else {
                             break;
                         }
                     }
 
+                    // Uncompressed output goes in `data`, which older servers can still
+                    // restore, and `chunks` continues from the first compressed chunk.
+                    let kept = &shell.data[prefix..];
+                    let split = kept
+                        .iter()
+                        .position(|chunk| !matches!(chunk, WsChunk::Raw(_)))
+                        .unwrap_or(kept.len());
+
                     let winsize = winsizes.get(sid).cloned().unwrap_or_default();
                     let shell = SerializedShell {
                         seqnum: shell.seqnum,
-                        data: shell.data[prefix..].to_vec(),
+                        data: kept[..split]
+                            .iter()
+                            .map(|chunk| chunk.data().clone())
+                            .collect(),
                         chunk_offset,
                         byte_offset,
                         closed: shell.closed,
@@ -55,6 +68,21 @@ impl Session {
                         winsize_y: winsize.y,
                         winsize_rows: winsize.rows.into(),
                         winsize_cols: winsize.cols.into(),
+                        exit_status: shell.exit.map(Into::into),
+                        info: shell.info.clone().map(|(_, data)| data).unwrap_or_default(),
+                        info_offset: shell.info.as_ref().map_or(0, |&(offset, _)| offset),
+                        chunks: kept[split..]
+                            .iter()
+                            .map(|chunk| {
+                                let (data, compression, size, offset) = chunk.clone().into_parts();
+                                SerializedChunk {
+                                    data,
+                                    compression,
+                                    size,
+                                    offset,
+                                }
+                            })
+                            .collect(),
                     };
                     (sid.0, shell)
                 })
@@ -63,6 +91,8 @@ impl Session {
             next_uid: ids.1 .0,
             name: self.metadata().name.clone(),
             write_password_hash: self.metadata().write_password_hash.clone(),
+            require_approval: self.metadata().require_approval,
+            read_only: self.read_only(),
         };
         let data = message.encode_to_vec();
         ensure!(data.len() < MAX_SNAPSHOT_SIZE, "snapshot too large");
@@ -78,6 +108,7 @@ impl Session {
             encrypted_zeros: message.encrypted_zeros,
             name: message.name,
             write_password_hash: message.write_password_hash,
+            require_approval: message.require_approval,
         };
 
         let session = Self::new(metadata);
@@ -93,12 +124,23 @@ impl Session {
                     cols: shell.winsize_cols.try_into().context("cols overflow")?,
                 },
             ));
+            // Uncompressed output comes first, followed by any compressed chunks.
+            let mut data: Vec<_> = shell.data.into_iter().map(WsChunk::Raw).collect();
+            for chunk in shell.chunks {
+                let chunk =
+                    WsChunk::from_parts(chunk.data, chunk.compression, chunk.size, chunk.offset);
+                data.push(chunk.map_err(Error::msg)?);
+            }
+            let stored_bytes = data.iter().map(|chunk| chunk.data().len() as u64).sum();
             let shell = State {
                 seqnum: shell.seqnum,
-                data: shell.data,
+                data,
                 chunk_offset: shell.chunk_offset,
                 byte_offset: shell.byte_offset,
+                stored_bytes,
                 closed: shell.closed,
+                exit: shell.exit_status.and_then(|status| status.try_into().ok()),
+                info: (!shell.info.is_empty()).then_some((shell.info_offset, shell.info)),
                 notify: Default::default(),
             };
             shells.insert(Sid(sid), shell);
@@ -108,6 +150,7 @@ impl Session {
         session
             .counter
             .set_current_values(Sid(message.next_sid), Uid(message.next_uid));
+        session.set_read_only(message.read_only);
 
         Ok(session)
     }
@@ -34,6 +34,9 @@ pub struct ServerState {
     /// Override the origin returned for the Open() RPC.
     override_origin: Option<String>,
 
+    /// Whether opening a session requires a verified client certificate.
+    require_client_cert: bool,
+
     /// A concurrent map of session IDs to session objects.
     store: DashMap<String, Arc<Session>>,
 
@@ -52,6 +55,7 @@ impl ServerState {
         Ok(Self {
             mac: Hmac::new_from_slice(secret.as_bytes()).unwrap(),
             override_origin: options.override_origin,
+            require_client_cert: options.client_ca.is_some(),
             store: DashMap::new(),
             mesh,
         })
@@ -67,6 +71,11 @@ impl ServerState {
         self.override_origin.clone()
     }
 
+    /// Returns whether opening a session requires a client certificate.
+    pub fn require_client_cert(&self) -> bool {
+        self.require_client_cert
+    }
+
     /// Lookup a local session by name.
     pub fn lookup(&self, name: &str) -> Option<Arc<Session>> {
         self.store.get(name).map(|s| s.clone())
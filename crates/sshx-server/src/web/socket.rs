@@ -10,16 +10,21 @@ use axum::response::IntoResponse;
 use bytes::Bytes;
 use futures_util::SinkExt;
 use sshx_core::proto::{server_update::ServerMessage, NewShell, TerminalInput, TerminalSize};
-use sshx_core::Sid;
+use sshx_core::{Sid, Uid};
 use subtle::ConstantTimeEq;
 use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
 use tokio_stream::StreamExt;
 use tracing::{error, info_span, warn, Instrument};
 
 use crate::session::Session;
-use crate::web::protocol::{WsClient, WsServer};
+use crate::web::protocol::{WsChunk, WsClient, WsServer};
 use crate::ServerState;
 
+/// How long to wait for a new user to choose a name before asking the host to
+/// let them in.
+const JOIN_NAME_WAIT: Duration = Duration::from_secs(1);
+
 pub async fn get_session_ws(
     Path(name): Path<String>,
     ws: WebSocketUpgrade,
@@ -91,6 +96,44 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
         })
     }
 
+    /// Ask the host to let the user in. Returns the name that the user chose
+    /// if they were approved, or `None` if they were denied or left first.
+    async fn wait_for_approval(
+        socket: &mut WebSocket,
+        session: &Session,
+        user_id: Uid,
+    ) -> Result<Option<String>> {
+        send(socket, WsServer::WaitingApproval()).await?;
+
+        // Give the client a moment to send its name, so the host knows who is asking.
+        let mut name = format!("User {user_id}");
+        let deadline = Instant::now() + JOIN_NAME_WAIT;
+        while let Ok(msg) = time::timeout_at(deadline, recv(socket)).await {
+            match msg? {
+                Some(WsClient::SetName(new_name)) if !new_name.is_empty() => {
+                    name = new_name;
+                    break;
+                }
+                Some(_) => (),
+                None => return Ok(None),
+            }
+        }
+
+        let mut answer = session.request_join(user_id, &name).await?;
+        loop {
+            tokio::select! {
+                approved = &mut answer => return Ok(approved.unwrap_or(false).then_some(name)),
+                _ = session.terminated() => return Ok(None),
+                msg = recv(socket) => match msg? {
+                    Some(WsClient::SetName(new_name)) if !new_name.is_empty() => name = new_name,
+                    Some(WsClient::Ping(ts)) => send(socket, WsServer::Pong(ts)).await?,
+                    Some(_) => (), // ignore other messages until approved
+                    None => return Ok(None),
+                },
+            }
+        }
+    }
+
     let metadata = session.metadata();
     let user_id = session.counter().next_uid();
     session.sync_now();
@@ -127,19 +170,49 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
         }
     };
 
+    let mut approved_name = None;
+    if metadata.require_approval {
+        let result = wait_for_approval(socket, &session, user_id).await;
+        session.cancel_join(user_id);
+        match result? {
+            Some(name) => approved_name = Some(name),
+            None => {
+                send(socket, WsServer::JoinDenied()).await.ok();
+                return Ok(());
+            }
+        }
+    }
+
     let _user_guard = session.user_scope(user_id, can_write)?;
+    if let Some(name) = approved_name {
+        session.update_user(user_id, |user| user.name = name)?;
+    }
 
     let update_tx = session.update_tx(); // start listening for updates before any state reads
     let mut broadcast_stream = session.subscribe_broadcast();
     send(socket, WsServer::Users(session.list_users())).await?;
+    for (id, status) in session.exited_shells() {
+        send(socket, WsServer::ShellExited(id, status)).await?;
+    }
+    for (id, offset, data) in session.shell_infos() {
+        send(socket, WsServer::ShellInfo(id, offset, data)).await?;
+    }
 
     let mut subscribed = HashSet::new(); // prevent duplicate subscriptions
-    let (chunks_tx, mut chunks_rx) = mpsc::channel::<(Sid, u64, Vec<Bytes>)>(1);
+    let (chunks_tx, mut chunks_rx) = mpsc::channel::<(Sid, u64, Vec<WsChunk>)>(1);
 
     let mut shells_stream = session.subscribe_shells();
+    let mut kicks = session.subscribe_kicks();
     loop {
         let msg = tokio::select! {
             _ = session.terminated() => break,
+            Ok(id) = kicks.recv() => {
+                if id == user_id {
+                    send(socket, WsServer::Kicked()).await?;
+                    break;
+                }
+                continue;
+            }
             Some(result) = broadcast_stream.next() => {
                 let msg = result.context("client fell behind on broadcast stream")?;
                 send(socket, msg).await?;
@@ -202,7 +275,8 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
                     send(socket, WsServer::Error(err.to_string())).await?;
                     continue;
                 }
-                if let Some(winsize) = winsize {
+                // Shells that have exited can still be moved, but not resized.
+                if let Some(winsize) = winsize.filter(|_| !session.has_exited(id)) {
                     let msg = ServerMessage::Resize(TerminalSize {
                         id: id.0,
                         rows: winsize.rows as u32,
@@ -216,10 +290,13 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
                     send(socket, WsServer::Error(e.to_string())).await?;
                     continue;
                 }
+                let user_name = session.get_user(user_id).map(|user| user.name);
                 let input = TerminalInput {
                     id: id.0,
                     data,
                     offset,
+                    user_id: user_id.0,
+                    user_name: user_name.unwrap_or_default(),
                 };
                 update_tx.send(ServerMessage::Input(input)).await?;
             }
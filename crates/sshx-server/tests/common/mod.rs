@@ -1,20 +1,21 @@
 use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::{ensure, Result};
 use axum::serve::ListenerExt;
 use futures_util::{SinkExt, StreamExt};
 use http::StatusCode;
-use sshx::encrypt::Encrypt;
+use sshx::{compress::decode_chunk, encrypt::Encrypt, shell_info::ShellInfo};
 use sshx_core::proto::sshx_service_client::SshxServiceClient;
 use sshx_core::{Sid, Uid};
 use sshx_server::{
     state::ServerState,
-    web::protocol::{WsClient, WsServer, WsUser, WsWinsize},
-    Server,
+    web::protocol::{WsClient, WsExitStatus, WsServer, WsUser, WsWinsize},
+    Server, ServerOptions,
 };
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time;
 use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
@@ -24,6 +25,7 @@ use tonic::transport::Channel;
 pub struct TestServer {
     local_addr: SocketAddr,
     server: Arc<Server>,
+    tls: bool,
 }
 
 impl TestServer {
@@ -32,21 +34,36 @@ impl TestServer {
     /// Returns an object with the local address, as well as a custom [`Drop`]
     /// implementation that gracefully shuts down the server.
     pub async fn new() -> Self {
+        Self::with_options(Default::default()).await
+    }
+
+    /// Create a server with custom options, serving TLS if a certificate is
+    /// given.
+    pub async fn with_options(options: ServerOptions) -> Self {
         let listener = TcpListener::bind("[::1]:0").await.unwrap();
         let local_addr = listener.local_addr().unwrap();
 
-        let server = Arc::new(Server::new(Default::default()).unwrap());
+        let tls = options.tls_cert.is_some();
+        let server = Arc::new(Server::new(options).unwrap());
         {
             let server = Arc::clone(&server);
-            let listener = listener.tap_io(|tcp_stream| {
-                _ = tcp_stream.set_nodelay(true);
-            });
             tokio::spawn(async move {
-                server.listen(listener).await.unwrap();
+                if tls {
+                    server.listen_tls(listener).await.unwrap();
+                } else {
+                    let listener = listener.tap_io(|tcp_stream| {
+                        _ = tcp_stream.set_nodelay(true);
+                    });
+                    server.listen(listener).await.unwrap();
+                }
             });
         }
 
-        TestServer { local_addr, server }
+        TestServer {
+            local_addr,
+            server,
+            tls,
+        }
     }
 
     /// Returns the local TCP address of this server.
@@ -56,7 +73,8 @@ impl TestServer {
 
     /// Returns the HTTP/2 base endpoint URI for this server.
     pub fn endpoint(&self) -> String {
-        format!("http://{}", self.local_addr)
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{scheme}://{}", self.local_addr)
     }
 
     /// Returns the WebSocket endpoint for streaming connections to a session.
@@ -81,6 +99,101 @@ impl Drop for TestServer {
     }
 }
 
+/// A minimal HTTP CONNECT and SOCKS5 proxy, used for testing.
+///
+/// The protocol is detected from the first byte of each connection, and the
+/// destination of every tunnel is recorded.
+pub struct TestProxy {
+    local_addr: SocketAddr,
+    pub targets: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestProxy {
+    /// Start a proxy listening on an unused local port.
+    pub async fn new() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        {
+            let targets = Arc::clone(&targets);
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let targets = Arc::clone(&targets);
+                    tokio::spawn(async move {
+                        if let Ok((stream, target)) = Self::handshake(stream).await {
+                            targets.lock().unwrap().push(target.clone());
+                            let mut stream = stream;
+                            if let Ok(mut upstream) = TcpStream::connect(&target).await {
+                                io::copy_bidirectional(&mut stream, &mut upstream)
+                                    .await
+                                    .ok();
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        TestProxy {
+            local_addr,
+            targets,
+        }
+    }
+
+    /// Returns the proxy URL for a scheme, such as `http` or `socks5`.
+    pub fn url(&self, scheme: &str) -> String {
+        format!("{scheme}://{}", self.local_addr)
+    }
+
+    async fn handshake(stream: TcpStream) -> Result<(TcpStream, String)> {
+        let mut stream = BufReader::with_capacity(1, stream);
+        if stream.fill_buf().await?.first() == Some(&0x05) {
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await?;
+            stream.write_all(&[0x05, 0x00]).await?;
+            let mut header = [0; 4];
+            stream.read_exact(&mut header).await?;
+            let host = match header[3] {
+                0x01 => {
+                    let mut ip = [0; 4];
+                    stream.read_exact(&mut ip).await?;
+                    std::net::Ipv4Addr::from(ip).to_string()
+                }
+                0x04 => {
+                    let mut ip = [0; 16];
+                    stream.read_exact(&mut ip).await?;
+                    format!("[{}]", std::net::Ipv6Addr::from(ip))
+                }
+                _ => {
+                    let mut name = vec![0; stream.read_u8().await? as usize];
+                    stream.read_exact(&mut name).await?;
+                    String::from_utf8(name)?
+                }
+            };
+            let port = stream.read_u16().await?;
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            Ok((stream.into_inner(), format!("{host}:{port}")))
+        } else {
+            let mut request = String::new();
+            stream.read_line(&mut request).await?;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let target = match request.split_whitespace().collect::<Vec<_>>()[..] {
+                ["CONNECT", target, _] => target.to_string(),
+                _ => anyhow::bail!("unexpected proxy request: {request}"),
+            };
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+            Ok((stream.into_inner(), target))
+        }
+    }
+}
+
 /// A WebSocket client that interacts with the server, used for testing.
 pub struct ClientSocket {
     inner: WebSocketStream<MaybeTlsStream<TcpStream>>,
@@ -88,8 +201,13 @@ pub struct ClientSocket {
     write_encrypt: Option<Encrypt>,
 
     pub user_id: Uid,
+    pub waiting: bool,
+    pub denied: bool,
+    pub kicked: bool,
     pub users: BTreeMap<Uid, WsUser>,
     pub shells: BTreeMap<Sid, WsWinsize>,
+    pub exited: BTreeMap<Sid, WsExitStatus>,
+    pub info: BTreeMap<Sid, ShellInfo>,
     pub data: HashMap<Sid, String>,
     pub messages: Vec<(Uid, String, String)>,
     pub errors: Vec<String>,
@@ -106,8 +224,13 @@ impl ClientSocket {
             encrypt: Encrypt::new(key),
             write_encrypt: write_password.map(Encrypt::new),
             user_id: Uid(0),
+            waiting: false,
+            denied: false,
+            kicked: false,
             users: BTreeMap::new(),
             shells: BTreeMap::new(),
+            exited: BTreeMap::new(),
+            info: BTreeMap::new(),
             data: HashMap::new(),
             messages: Vec::new(),
             errors: Vec::new(),
@@ -164,6 +287,9 @@ impl ClientSocket {
                 match msg {
                     WsServer::Hello(user_id, _) => self.user_id = user_id,
                     WsServer::InvalidAuth() => panic!("invalid authentication"),
+                    WsServer::WaitingApproval() => self.waiting = true,
+                    WsServer::JoinDenied() => self.denied = true,
+                    WsServer::Kicked() => self.kicked = true,
                     WsServer::Users(users) => self.users = BTreeMap::from_iter(users),
                     WsServer::UserDiff(id, maybe_user) => {
                         self.users.remove(&id);
@@ -172,15 +298,20 @@ impl ClientSocket {
                         }
                     }
                     WsServer::Shells(shells) => self.shells = BTreeMap::from_iter(shells),
+                    WsServer::ShellExited(id, status) => {
+                        self.exited.insert(id, status);
+                    }
+                    WsServer::ShellInfo(id, offset, data) => {
+                        let stream_num = 0x300000000 | id.0 as u64;
+                        let json = self.encrypt.segment(stream_num, offset, &data);
+                        self.info.insert(id, serde_json::from_slice(&json).unwrap());
+                    }
                     WsServer::Chunks(id, seqnum, chunks) => {
                         let value = self.data.entry(id).or_default();
                         assert_eq!(seqnum, value.len() as u64);
-                        for buf in chunks {
-                            let plaintext = self.encrypt.segment(
-                                0x100000000 | id.0 as u64,
-                                value.len() as u64,
-                                &buf,
-                            );
+                        for chunk in chunks {
+                            let seq = value.len() as u64;
+                            let plaintext = decode_chunk(&self.encrypt, id, seq, &chunk).unwrap();
                             value.push_str(std::str::from_utf8(&plaintext).unwrap());
                         }
                     }
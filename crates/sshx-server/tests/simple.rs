@@ -16,6 +16,8 @@ async fn test_rpc() -> Result<()> {
         encrypted_zeros: Encrypt::new("").zeros().into(),
         name: String::new(),
         write_password_hash: None,
+        require_approval: false,
+        compression: Vec::new(),
     };
     let resp = client.open(req).await?;
     assert!(!resp.into_inner().name.is_empty());
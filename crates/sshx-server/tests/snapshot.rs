@@ -1,8 +1,9 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use prost::Message;
 use sshx::{controller::Controller, runner::Runner};
-use sshx_core::{Sid, Uid};
+use sshx_core::{proto::SerializedSession, Sid, Uid};
 use sshx_server::{
     session::Session,
     web::protocol::{WsClient, WsWinsize},
@@ -43,6 +44,13 @@ async fn test_basic_restore() -> Result<()> {
 
     // Replace the shell with its snapshot.
     let data = server.state().lookup(&name).unwrap().snapshot()?;
+
+    // Uncompressed output stays readable by servers that predate compression.
+    let message = SerializedSession::decode(&*zstd::bulk::decompress(&data, 1 << 22)?)?;
+    let shell = &message.shells[&1];
+    assert_eq!(shell.data.iter().map(|data| data.len()).sum::<usize>(), 30);
+    assert!(shell.chunks.is_empty());
+
     server
         .state()
         .insert(&name, Arc::new(Session::restore(&data)?));
@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use sha2::{Digest, Sha256};
+use sshx::{
+    controller::Controller,
+    runner::Runner,
+    transport::{TlsOptions, Transport},
+};
+use sshx_server::ServerOptions;
+
+use crate::common::*;
+
+pub mod common;
+
+/// Certificates for a private CA, a server and a client, written to files.
+struct TestPki {
+    dir: PathBuf,
+    server_pin: String,
+}
+
+impl TestPki {
+    fn new(name: &str) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("sshx-test-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        let ca_key = KeyPair::generate()?;
+        let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key)?;
+        fs::write(dir.join("ca.pem"), ca.pem())?;
+
+        let issue = |names: Vec<String>, usage, file: &str| -> Result<KeyPair> {
+            let key = KeyPair::generate()?;
+            let mut params = CertificateParams::new(names)?;
+            params.extended_key_usages = vec![usage];
+            let cert: Certificate = params.signed_by(&key, &ca, &ca_key)?;
+            fs::write(dir.join(format!("{file}.pem")), cert.pem())?;
+            fs::write(dir.join(format!("{file}.key")), key.serialize_pem())?;
+            Ok(key)
+        };
+        let server_key = issue(
+            vec!["localhost".into(), "::1".into()],
+            ExtendedKeyUsagePurpose::ServerAuth,
+            "server",
+        )?;
+        issue(vec![], ExtendedKeyUsagePurpose::ClientAuth, "client")?;
+
+        let server_pin = BASE64_STANDARD.encode(Sha256::digest(server_key.public_key_der()));
+        Ok(Self { dir, server_pin })
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn server_options(&self, client_ca: bool) -> ServerOptions {
+        let mut options = ServerOptions::default();
+        options.tls_cert = Some(self.path("server.pem"));
+        options.tls_key = Some(self.path("server.key"));
+        options.client_ca = client_ca.then(|| self.path("ca.pem"));
+        options
+    }
+
+    fn client_identity(&self) -> Option<(PathBuf, PathBuf)> {
+        Some((self.path("client.pem"), self.path("client.key")))
+    }
+}
+
+impl Drop for TestPki {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+async fn open(server: &TestServer, tls: TlsOptions) -> Result<Controller> {
+    let transport = Transport::new(&server.endpoint())?.with_tls(&tls)?;
+    Controller::new_with_transport(transport, "", Runner::Echo, false, false).await
+}
+
+fn ca_only(ca: &Path) -> TlsOptions {
+    TlsOptions {
+        ca_cert: Some(ca.into()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_private_ca() -> Result<()> {
+    let pki = TestPki::new("private-ca")?;
+    let server = TestServer::with_options(pki.server_options(false)).await;
+
+    let controller = open(&server, ca_only(&pki.path("ca.pem"))).await?;
+    controller.close().await?;
+
+    // Without the private CA, the server certificate is not trusted.
+    assert!(open(&server, TlsOptions::default()).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_cert_required() -> Result<()> {
+    let pki = TestPki::new("client-cert")?;
+    let server = TestServer::with_options(pki.server_options(true)).await;
+
+    let tls = TlsOptions {
+        client_identity: pki.client_identity(),
+        ..ca_only(&pki.path("ca.pem"))
+    };
+    let controller = open(&server, tls).await?;
+    controller.close().await?;
+
+    // Browsers do not present a certificate, but can still load the web app.
+    let ca = reqwest::Certificate::from_pem(&fs::read(pki.path("ca.pem"))?)?;
+    let client = reqwest::Client::builder()
+        .add_root_certificate(ca)
+        .build()?;
+    let resp = client.get(server.endpoint()).send().await?;
+    assert!(!resp.status().is_server_error());
+
+    let Err(err) = open(&server, ca_only(&pki.path("ca.pem"))).await else {
+        panic!("opening a session without a client certificate should fail");
+    };
+    assert!(err.to_string().contains("client certificate"), "{err:?}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pinned_key() -> Result<()> {
+    let pki = TestPki::new("pinned-key")?;
+    let server = TestServer::with_options(pki.server_options(false)).await;
+
+    let tls = TlsOptions {
+        pin_sha256: vec![format!("sha256//{}", pki.server_pin)],
+        ..ca_only(&pki.path("ca.pem"))
+    };
+    let controller = open(&server, tls).await?;
+    controller.close().await?;
+
+    let tls = TlsOptions {
+        pin_sha256: vec![BASE64_STANDARD.encode([0; 32])],
+        ..ca_only(&pki.path("ca.pem"))
+    };
+    assert!(open(&server, tls).await.is_err());
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::Result;
+use sshx::{controller::Controller, runner::Runner, tmux::TmuxSession};
+use sshx_core::{ws::WsClient, Sid};
+
+use crate::common::*;
+
+pub mod common;
+
+/// Private tmux server for a test, which is killed when dropped.
+struct TestTmux;
+
+impl TestTmux {
+    /// Start a session with two windows, or return `None` without tmux.
+    fn new(session: &str) -> Option<Self> {
+        let dir = std::env::temp_dir().join(format!("sshx-test-tmux-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).ok()?;
+        // Use a server of our own, even when the tests are run inside tmux.
+        std::env::remove_var("TMUX");
+        std::env::set_var("TMUX_TMPDIR", &dir);
+        let tmux = Self;
+        tmux.run(&["new-session", "-d", "-s", session, "-x", "80", "-y", "24"])?;
+        tmux.run(&["new-window", "-d", "-t", session])?;
+        Some(tmux)
+    }
+
+    fn run(&self, args: &[&str]) -> Option<String> {
+        let output = Command::new("tmux").args(args).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn windows(&self, session: &str) -> usize {
+        let windows = self.run(&["list-windows", "-t", session]);
+        windows.map_or(0, |windows| windows.lines().count())
+    }
+}
+
+impl Drop for TestTmux {
+    fn drop(&mut self) {
+        self.run(&["kill-server"]);
+    }
+}
+
+/// Wait until a condition holds, since changes pass through tmux and shells
+/// can be slow to start.
+async fn wait_until(s: &mut ClientSocket, cond: impl Fn(&ClientSocket) -> bool) -> bool {
+    for _ in 0..100 {
+        s.flush().await;
+        if cond(s) {
+            return true;
+        }
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_tmux_windows() -> Result<()> {
+    let Some(tmux) = TestTmux::new("sshx-test") else {
+        eprintln!("skipping test, tmux is not available");
+        return Ok(());
+    };
+    let server = TestServer::new().await;
+
+    let session = TmuxSession::attach("sshx-test").await?;
+    let runner = Runner::Tmux { session };
+    let mut controller = Controller::new(&server.endpoint(), "", runner, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    // Existing windows are announced as shells.
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    assert!(wait_until(&mut s, |s| s.shells.len() == 2).await);
+
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.send_input(Sid(1), b"echo sshx$((6 * 7))\r").await;
+    let echoed = wait_until(&mut s, |s| s.read(Sid(1)).contains("sshx42")).await;
+    assert!(echoed, "{}", s.read(Sid(1)));
+
+    // Shells and windows are opened and closed on either side.
+    s.send(WsClient::Create(0, 0)).await;
+    assert!(wait_until(&mut s, |s| s.shells.len() == 3).await);
+    assert_eq!(tmux.windows("sshx-test"), 3);
+
+    s.send(WsClient::Close(Sid(3))).await;
+    assert!(wait_until(&mut s, |s| s.shells.len() == 2).await);
+    for _ in 0..20 {
+        if tmux.windows("sshx-test") == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(tmux.windows("sshx-test"), 2);
+
+    tmux.run(&["new-window", "-d", "-t", "sshx-test"]);
+    assert!(wait_until(&mut s, |s| s.shells.len() == 3).await);
+
+    tmux.run(&["kill-window", "-t", "sshx-test:0"]);
+    assert!(wait_until(&mut s, |s| !s.shells.contains_key(&Sid(1))).await);
+    assert_eq!(s.shells.len(), 2);
+
+    Ok(())
+}
@@ -1,10 +1,17 @@
 use anyhow::{Context, Result};
-use sshx::{controller::Controller, encrypt::Encrypt, runner::Runner};
+use sshx::{
+    controller::{Controller, RetryPolicy},
+    encrypt::Encrypt,
+    runner::Runner,
+    shell_info::ShellInfo,
+    terminal::{Command, Limits},
+    transport::{Proxy, Transport},
+};
 use sshx_core::{
     proto::{server_update::ServerMessage, NewShell, TerminalInput},
     Sid, Uid,
 };
-use sshx_server::web::protocol::{WsClient, WsWinsize};
+use sshx_server::web::protocol::{WsClient, WsExitStatus, WsWinsize};
 use tokio::time::{self, Duration};
 
 use crate::common::*;
@@ -15,6 +22,7 @@ pub mod common;
 async fn test_handshake() -> Result<()> {
     let server = TestServer::new().await;
     let controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    assert!(!controller.credentials().compress);
     controller.close().await?;
     Ok(())
 }
@@ -41,6 +49,7 @@ async fn test_command() -> Result<()> {
         id: 1,
         data: encrypt.segment(0x200000000, offset, b"ls\r\n").into(),
         offset,
+        ..Default::default()
     };
     updates.send(ServerMessage::Input(data)).await?;
 
@@ -229,6 +238,186 @@ async fn test_chat_messages() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_input_attribution() -> Result<()> {
+    let server = TestServer::new().await;
+
+    // The controller is not run, so we can read its updates from the session.
+    let controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::SetName("alice".into())).await;
+    s.send_input(Sid(1), b"ls\r").await;
+    s.flush().await;
+
+    let session = server.state().lookup(&name).context("missing session")?;
+    let input = loop {
+        match session.update_rx().recv().await? {
+            ServerMessage::Input(input) => break input,
+            _ => continue,
+        }
+    };
+    assert_eq!(input.id, 1);
+    assert_eq!(Uid(input.user_id), s.user_id);
+    assert_eq!(input.user_name, "alice");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_approval() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let transport = Transport::new(&server.endpoint())?;
+    let mut controller =
+        Controller::new_with_transport(transport, "", Runner::Echo, false, true).await?;
+    let mut requests = controller.join_requests();
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s1.send(WsClient::SetName("alice".into())).await;
+    s1.flush().await;
+    assert!(s1.waiting);
+    assert!(
+        s1.users.is_empty(),
+        "waiting users should not see the session"
+    );
+
+    let request = requests.recv().await.context("missing join request")?;
+    assert_eq!(request.user_id, s1.user_id);
+    assert_eq!(request.name, "alice");
+    request.answer(true).await?;
+    s1.flush().await;
+    let user = s1
+        .users
+        .get(&s1.user_id)
+        .context("approved user is missing")?;
+    assert_eq!(user.name, "alice");
+
+    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s2.send(WsClient::SetName("mallory".into())).await;
+    let request = requests.recv().await.context("missing join request")?;
+    assert_eq!(request.name, "mallory");
+    request.answer(false).await?;
+    s2.flush().await;
+    assert!(s2.denied);
+    assert!(s2.users.is_empty());
+
+    s1.flush().await;
+    assert_eq!(s1.users.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_control_socket() -> Result<()> {
+    use sshx::control::{request, ControlSocket, Request, Response};
+
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    let path = std::env::temp_dir()
+        .join(format!("sshx-test-{}", std::process::id()))
+        .join(format!("{name}.sock"));
+    let socket = ControlSocket::bind(&path, controller.handle())?;
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::SetName("alice".into())).await;
+    s.flush().await;
+
+    let Response::Users { users, read_only } = request(&path, &Request::Users).await? else {
+        panic!("expected a list of users");
+    };
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].id, s.user_id);
+    assert_eq!(users[0].name, "alice");
+    assert!(users[0].can_write && !read_only);
+
+    let resp = request(&path, &Request::SetReadOnly { read_only: true }).await?;
+    assert_eq!(resp, Response::Ok);
+    s.flush().await;
+    assert!(!s.users[&s.user_id].can_write);
+    request(&path, &Request::SetReadOnly { read_only: false }).await?;
+    s.flush().await;
+    assert!(s.users[&s.user_id].can_write);
+
+    request(&path, &Request::CreateShell).await?;
+    s.flush().await;
+    assert!(s.shells.contains_key(&Sid(1)));
+    request(&path, &Request::CloseShell { id: Sid(1) }).await?;
+    s.flush().await;
+    assert!(s.shells.is_empty());
+
+    let resp = request(&path, &Request::Kick { user_id: Uid(100) }).await?;
+    assert!(matches!(resp, Response::Error { .. }));
+    request(&path, &Request::Kick { user_id: s.user_id }).await?;
+    s.flush().await;
+    assert!(s.kicked);
+
+    drop(socket);
+    assert!(!path.exists());
+    std::fs::remove_dir(path.parent().unwrap())?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_initial_shells() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    controller.open_shells(&[(0, 0), (780, 0)]);
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+    assert_eq!(
+        s.shells.keys().copied().collect::<Vec<_>>(),
+        [Sid(1), Sid(2)]
+    );
+    assert_eq!(s.shells[&Sid(2)].x, 780);
+
+    // Shells created by users never reuse the IDs that the client picked.
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    assert!(s.shells.contains_key(&Sid(3)));
+    assert!(s.errors.is_empty(), "{:?}", s.errors);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipe_read_only() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Pipe, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    let handle = controller.handle();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+    assert_eq!(s.shells.len(), 1);
+    assert!(!s.users[&s.user_id].can_write);
+
+    // The piped shell is the only one, even when the host asks for another.
+    handle.create_shell().await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    assert_eq!(s.shells.len(), 1);
+    assert!(!s.errors.is_empty());
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_read_write_permissions() -> Result<()> {
     let server = TestServer::new().await;
@@ -283,3 +472,354 @@ async fn test_read_write_permissions() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_resume_session() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let credentials = controller.credentials();
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    let task = tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.send_input(Sid(1), b"hello").await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "hello");
+
+    // Simulate the client process exiting without closing the session.
+    task.abort();
+    let mut controller = Controller::resume(credentials.clone(), Runner::Echo)
+        .await?
+        .context("session should still exist")?;
+    assert_eq!(controller.name(), name);
+    assert_eq!(controller.credentials(), credentials);
+    tokio::spawn(async move { controller.run().await });
+    time::sleep(Duration::from_millis(100)).await;
+
+    s.send_input(Sid(1), b" again").await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "hello again");
+
+    server.state().close_session(&name).await?;
+    assert!(Controller::resume(credentials, Runner::Echo)
+        .await?
+        .is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy() -> Result<()> {
+    let server = TestServer::new().await;
+    let proxy = TestProxy::new().await;
+
+    for scheme in ["http", "socks5", "socks5h"] {
+        let transport =
+            Transport::new(&server.endpoint())?.with_proxy(Some(Proxy::parse(&proxy.url(scheme))?));
+        let mut controller =
+            Controller::new_with_transport(transport, "", Runner::Echo, false, false).await?;
+        let name = controller.name().to_owned();
+        let key = controller.encryption_key().to_owned();
+        tokio::spawn(async move { controller.run().await });
+
+        let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+        s.flush().await;
+        s.send(WsClient::Create(0, 0)).await;
+        s.flush().await;
+        s.send(WsClient::Subscribe(Sid(1), 0)).await;
+        s.send_input(Sid(1), b"hello proxy").await;
+        s.flush().await;
+        assert_eq!(s.read(Sid(1)), "hello proxy");
+    }
+
+    let targets = proxy.targets.lock().unwrap().clone();
+    assert!(
+        targets.len() >= 3,
+        "each controller should connect through the proxy"
+    );
+    assert!(targets
+        .iter()
+        .all(|target| *target == server.local_addr().to_string()));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shell_exit_status() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let command = Command {
+        program: "/bin/sh".into(),
+        args: vec!["-c".into(), "echo bye; exit 3".into()],
+        ..Default::default()
+    };
+    let runner = Runner::Command(command);
+    let mut controller = Controller::new(&server.endpoint(), "", runner, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    controller.open_shells(&[(0, 0)]);
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    for _ in 0..40 {
+        s.flush().await;
+        if !s.exited.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(s.exited.get(&Sid(1)), Some(&WsExitStatus::Code(3)));
+
+    // The shell stays open with its final output until a user closes it.
+    assert!(s.shells.contains_key(&Sid(1)));
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.flush().await;
+    assert!(s.read(Sid(1)).contains("bye"));
+
+    // Users who join later also learn that the shell has exited.
+    let mut s2 = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s2.flush().await;
+    assert_eq!(s2.exited.get(&Sid(1)), Some(&WsExitStatus::Code(3)));
+
+    s.send(WsClient::Close(Sid(1))).await;
+    s.flush().await;
+    assert!(s.shells.is_empty());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shell_info() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let command = Command {
+        program: "/bin/sh".into(),
+        args: vec![
+            "-c".into(),
+            r"printf '\033]2;my title\007\033]7;file://host/tmp/a%%20b\033\\'; exec sleep 10"
+                .into(),
+        ],
+        ..Default::default()
+    };
+    let runner = Runner::Command(command);
+    let mut controller = Controller::new(&server.endpoint(), "", runner, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    controller.open_shells(&[(0, 0)]);
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    // The foreground process is checked every second, and may still be `sh`.
+    let sleeping = |info: &ShellInfo| info.process.as_deref() == Some("sleep");
+    for _ in 0..60 {
+        s.flush().await;
+        let info = s.info.get(&Sid(1));
+        if info.is_some_and(|info| {
+            info.cwd.is_some() && (!cfg!(target_os = "linux") || sleeping(info))
+        }) {
+            break;
+        }
+    }
+    let info = &s.info[&Sid(1)];
+    assert_eq!(info.title.as_deref(), Some("my title"));
+    assert_eq!(info.cwd.as_deref(), Some("/tmp/a b"));
+    if cfg!(target_os = "linux") {
+        assert_eq!(info.process.as_deref(), Some("sleep"));
+    }
+
+    // Users who join later get the latest info.
+    let mut s2 = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s2.flush().await;
+    assert_eq!(s2.info.get(&Sid(1)), Some(info));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_limit_notice() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let path = std::env::temp_dir().join(format!("sshx-limit-notice-{}", std::process::id()));
+    let command = Command {
+        program: "/bin/sh".into(),
+        args: vec![
+            "-c".into(),
+            format!("exec head -c 4096 /dev/zero > {}", path.display()),
+        ],
+        limits: Limits {
+            memory: Some(1 << 30),
+            file_size: Some(1024),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let runner = Runner::Command(command);
+    let mut controller = Controller::new(&server.endpoint(), "", runner, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    controller.open_shells(&[(0, 0)]);
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    for _ in 0..40 {
+        s.flush().await;
+        if !s.exited.is_empty() {
+            break;
+        }
+    }
+    std::fs::remove_file(&path)?;
+    assert!(s.exited.contains_key(&Sid(1)));
+
+    // Everyone watching the shell is told which limit ended it.
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.flush().await;
+    assert!(
+        s.read(Sid(1)).contains("file size limit"),
+        "{}",
+        s.read(Sid(1))
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_compressed_output() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let command = Command {
+        program: "/bin/sh".into(),
+        args: vec![
+            "-c".into(),
+            "yes hello 2>/dev/null | head -n 5000; exec sleep 10".into(),
+        ],
+        ..Default::default()
+    };
+    let transport = Transport::new(&server.endpoint())?;
+    let mut controller = Controller::builder(transport, Runner::Command(command))
+        .compress(true)
+        .build()
+        .await?;
+    assert!(controller.credentials().compress);
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    controller.open_shells(&[(0, 0)]);
+    tokio::spawn(async move { controller.run().await });
+
+    let expected = "hello\r\n".repeat(5000);
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    for _ in 0..40 {
+        s.flush().await;
+        if s.read(Sid(1)).len() >= expected.len() {
+            break;
+        }
+    }
+    assert_eq!(s.read(Sid(1)), expected);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_output_throttled() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let command = Command {
+        program: "/bin/sh".into(),
+        args: vec!["-c".into(), "seq 1 100000; echo done; exec sleep 10".into()],
+        ..Default::default()
+    };
+    let runner = Runner::Command(command);
+    let mut controller = Controller::new(&server.endpoint(), "", runner, false).await?;
+    controller.limit_output_rate(16 << 10);
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    controller.open_shells(&[(0, 0)]);
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    for _ in 0..40 {
+        s.flush().await;
+        if s.read(Sid(1)).ends_with("done\r\n") {
+            break;
+        }
+    }
+
+    // Most of the output is skipped, but the latest screen is kept.
+    let output = s.read(Sid(1));
+    assert!(output.contains("[sshx: output throttled"), "{output}");
+    assert!(output.ends_with("99999\r\n100000\r\ndone\r\n"), "{output}");
+    assert!(output.len() < 100 << 10);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_builder_stop() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let transport = Transport::new(&server.endpoint())?;
+    let mut controller = Controller::builder(transport, Runner::Echo)
+        .name("embedded")
+        .encryption_key("customkey123")
+        .write_password("custompassword")
+        .shell_capacity(4)
+        .output_capacity(8)
+        .heartbeat_interval(Duration::from_millis(500))
+        .build()
+        .await?;
+    assert!(controller.url().ends_with("#customkey123"));
+    assert!(controller
+        .write_url()
+        .unwrap()
+        .ends_with("#customkey123,custompassword"));
+    let name = controller.name().to_owned();
+    let handle = controller.handle();
+    let task = tokio::spawn(async move { controller.run().await.map(|()| controller) });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s = ClientSocket::connect(&endpoint, "customkey123", Some("custompassword")).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.send_input(Sid(1), b"hello").await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "hello");
+
+    // Stopping the controller lets it be closed cleanly afterward.
+    handle.stop();
+    let controller = time::timeout(Duration::from_secs(1), task).await???;
+    controller.close().await?;
+    assert!(server.state().lookup(&name).is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retry_gives_up() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let transport = Transport::new(&server.endpoint())?;
+    let retry = RetryPolicy {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        max_retries: Some(2),
+    };
+    let mut controller = Controller::builder(transport, Runner::Echo)
+        .retry_policy(retry)
+        .build()
+        .await?;
+    let name = controller.name().to_owned();
+    let task = tokio::spawn(async move { controller.run().await });
+    time::sleep(Duration::from_millis(100)).await;
+
+    // The session no longer exists, so every reconnection fails.
+    server.state().close_session(&name).await?;
+    let result = time::timeout(Duration::from_secs(1), task).await??;
+    assert!(result.is_err());
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Compression of terminal output before it is encrypted.
+//!
+//! Output is encrypted in counter mode, so it cannot be compressed once it
+//! leaves the client. Each chunk is instead compressed on its own as a raw
+//! deflate stream, which browsers can read with `DecompressionStream`. The
+//! compressed bytes are encrypted in a separate stream at a running offset,
+//! while sequence numbers still count bytes of the original output.
+
+use std::io::{Read, Write};
+
+use anyhow::{ensure, Context, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use sshx_core::{ws::WsChunk, Sid};
+
+use crate::encrypt::Encrypt;
+
+/// Compress data as a raw deflate stream, if that makes it smaller.
+pub fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Decompress a raw deflate stream, which must expand to exactly `size` bytes.
+pub fn inflate(data: &[u8], size: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    // Read one extra byte, so that we can tell if the stream is too long.
+    DeflateDecoder::new(data)
+        .take(size + 1)
+        .read_to_end(&mut buf)
+        .context("invalid compressed terminal data")?;
+    ensure!(
+        buf.len() as u64 == size,
+        "compressed terminal data has the wrong length"
+    );
+    Ok(buf)
+}
+
+/// Decrypt a chunk of output from a shell, which starts at sequence number
+/// `seq` in the output stream.
+pub fn decode_chunk(encrypt: &Encrypt, id: Sid, seq: u64, chunk: &WsChunk) -> Result<Vec<u8>> {
+    match chunk {
+        WsChunk::Raw(data) => Ok(encrypt.segment(0x100000000 | id.0 as u64, seq, data)),
+        WsChunk::Deflate { data, size, offset } => {
+            let compressed = encrypt.segment(0x400000000 | id.0 as u64, *offset, data);
+            inflate(&compressed, *size)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deflate, inflate};
+
+    #[test]
+    fn roundtrip_deflate() {
+        let data = "hello world\r\n".repeat(100);
+        let compressed = deflate(data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len());
+        let decompressed = inflate(&compressed, data.len() as u64).unwrap();
+        assert_eq!(decompressed, data.as_bytes());
+    }
+
+    #[test]
+    fn skip_incompressible() {
+        assert_eq!(deflate(b"ls\r\n"), None);
+    }
+
+    #[test]
+    fn reject_wrong_size() {
+        let data = "abc".repeat(100);
+        let compressed = deflate(data.as_bytes()).unwrap();
+        assert!(inflate(&compressed, 299).is_err());
+        assert!(inflate(&compressed, 301).is_err());
+    }
+}
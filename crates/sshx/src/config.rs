@@ -0,0 +1,150 @@
+//! Configuration file with named profiles of default options.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// Name of the profile used when none is selected.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Contents of the client configuration file.
+///
+/// ```toml
+/// [profile.default]
+/// name = "my-laptop"
+///
+/// [profile.work]
+/// server = "https://sshx.internal"
+/// enable-readers = true
+/// ```
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Named profiles of options.
+    #[serde(default)]
+    pub profile: HashMap<String, Profile>,
+}
+
+/// Default values for command-line options, overridden by flags and the
+/// environment.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    /// Address of the remote sshx server.
+    pub server: Option<String>,
+    /// Proxy to connect to the server through.
+    pub proxy: Option<String>,
+    /// PEM file with extra root certificates to trust.
+    pub ca_cert: Option<PathBuf>,
+    /// PEM file with a client certificate to authenticate with.
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate.
+    pub client_key: Option<PathBuf>,
+    /// Base64 SHA-256 hashes of public keys, one of which the server must use.
+    pub pin_sha256: Option<Vec<String>>,
+    /// Local shell command to run in the terminal.
+    pub shell: Option<String>,
+    /// Session name displayed in the title.
+    pub name: Option<String>,
+    /// Whether to generate separate URLs for viewers and editors.
+    pub enable_readers: Option<bool>,
+    /// Whether to only print the URL to stdout.
+    pub quiet: Option<bool>,
+}
+
+impl Config {
+    /// Returns the path of the configuration file,
+    /// `~/.config/sshx/config.toml`.
+    ///
+    /// This respects `XDG_CONFIG_HOME` if it is set.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => {
+                let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+                PathBuf::from(home).join(".config")
+            }
+        };
+        Some(config_dir.join("sshx").join("config.toml"))
+    }
+
+    /// Read the configuration from a file, or return an empty one if it does
+    /// not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Parse the configuration from TOML text.
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Look up a profile by name.
+    ///
+    /// With no name, this is the `default` profile if there is one, otherwise
+    /// an empty profile. It is an error to select a profile that is missing.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name {
+            Some(name) => match self.profile.get(name) {
+                Some(profile) => Ok(profile.clone()),
+                None => bail!("profile {name:?} is not defined in the config file"),
+            },
+            None => Ok(self
+                .profile
+                .get(DEFAULT_PROFILE)
+                .cloned()
+                .unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Profile};
+
+    #[test]
+    fn parse_profiles() {
+        let config = Config::parse(
+            r#"
+            [profile.default]
+            name = "laptop"
+
+            [profile.work]
+            server = "https://sshx.internal"
+            enable-readers = true
+            "#,
+        )
+        .unwrap();
+
+        let profile = config.profile(None).unwrap();
+        assert_eq!(profile.name.as_deref(), Some("laptop"));
+        assert_eq!(profile.server, None);
+
+        let profile = config.profile(Some("work")).unwrap();
+        assert_eq!(profile.server.as_deref(), Some("https://sshx.internal"));
+        assert_eq!(profile.enable_readers, Some(true));
+        assert_eq!(profile.name, None);
+
+        assert!(config.profile(Some("home")).is_err());
+    }
+
+    #[test]
+    fn empty_config() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.profile(None).unwrap(), Profile::default());
+        assert!(config.profile(Some("default")).is_err());
+    }
+
+    #[test]
+    fn unknown_option() {
+        assert!(Config::parse("[profile.work]\nsevrer = \"https://sshx.io\"").is_err());
+    }
+}
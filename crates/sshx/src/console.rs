@@ -0,0 +1,139 @@
+//! Access to the local terminal that the sshx client is running in.
+
+#![allow(unsafe_code)]
+
+use std::io::{self as std_io, IsTerminal, Read};
+use std::os::fd::AsRawFd;
+use std::thread;
+
+use anyhow::{ensure, Result};
+use nix::libc::TIOCGWINSZ;
+use nix::pty::Winsize;
+use nix::sys::termios::{self, SetArg, Termios};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+use crate::runner::ShellData;
+
+/// Guard that puts the local terminal into raw mode until it is dropped.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    /// Switch standard input to raw mode, remembering the previous settings.
+    pub fn enable() -> Result<Self> {
+        let stdin = std_io::stdin();
+        ensure!(stdin.is_terminal(), "standard input is not a terminal");
+        let original = termios::tcgetattr(&stdin)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &raw)?;
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        termios::tcsetattr(std_io::stdin(), SetArg::TCSANOW, &self.original).ok();
+    }
+}
+
+/// Returns the number of rows and columns of the local terminal.
+pub fn get_winsize() -> Result<(u16, u16)> {
+    nix::ioctl_read_bad!(ioctl_get_winsize, TIOCGWINSZ, Winsize);
+    let mut winsize = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // Safety: The file descriptor is valid for the lifetime of the process.
+    unsafe { ioctl_get_winsize(std_io::stdout().as_raw_fd(), &mut winsize) }?;
+    Ok((winsize.ws_row, winsize.ws_col))
+}
+
+/// Connect the local terminal to a shell in both directions.
+///
+/// Keystrokes are sent to the shell as input, and the shell follows the size
+/// of the local window. Output is read from `output_rx` until the shell exits,
+/// at which point the terminal is restored and `done_tx` is notified.
+pub async fn mirror(
+    shell_tx: mpsc::WeakSender<ShellData>,
+    mut output_rx: mpsc::Receiver<String>,
+    done_tx: oneshot::Sender<()>,
+) {
+    if let Err(err) = mirror_inner(shell_tx, &mut output_rx).await {
+        warn!(?err, "stopped mirroring shell in local terminal");
+        // Keep draining output, so that the shell is not blocked on us.
+        while output_rx.recv().await.is_some() {}
+    }
+    done_tx.send(()).ok();
+}
+
+async fn mirror_inner(
+    shell_tx: mpsc::WeakSender<ShellData>,
+    output_rx: &mut mpsc::Receiver<String>,
+) -> Result<()> {
+    let _raw_mode = RawMode::enable()?;
+    let mut sigwinch = signal(SignalKind::window_change())?;
+    let mut stdin_rx = read_stdin();
+    let mut stdout = io::stdout();
+
+    // Hold a weak reference, so the shell can still be closed by the server.
+    let send = |data: ShellData| {
+        let shell_tx = shell_tx.upgrade();
+        async move {
+            if let Some(shell_tx) = shell_tx {
+                shell_tx.send(data).await.ok();
+            }
+        }
+    };
+
+    let (rows, cols) = get_winsize()?;
+    send(ShellData::Size(rows.into(), cols.into())).await;
+
+    loop {
+        tokio::select! {
+            output = output_rx.recv() => {
+                let Some(output) = output else {
+                    debug!("mirrored shell has exited");
+                    return Ok(());
+                };
+                stdout.write_all(output.as_bytes()).await?;
+                stdout.flush().await?;
+            }
+            Some(data) = stdin_rx.recv() => {
+                send(ShellData::Data(data, None)).await;
+            }
+            Some(()) = sigwinch.recv() => {
+                let (rows, cols) = get_winsize()?;
+                send(ShellData::Size(rows.into(), cols.into())).await;
+            }
+        }
+    }
+}
+
+/// Read from standard input on a separate thread.
+///
+/// This avoids `tokio::io::stdin()`, which blocks the runtime from shutting
+/// down while a read is pending.
+pub fn read_stdin() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(16);
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match std_io::stdin().read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.blocking_send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,266 @@
+//! Local control socket for managing a running session from the command line.
+//!
+//! Each request and response is a single line of JSON, sent over a Unix domain
+//! socket that only the user running `sshx` can connect to.
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use sshx_core::{Sid, Uid};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::controller::{ControllerHandle, User};
+
+/// Request sent to a running session over its control socket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// List the users connected to the session.
+    Users,
+    /// Get the links to the session.
+    Urls,
+    /// Disconnect a user from the session.
+    Kick {
+        /// ID of the user.
+        user_id: Uid,
+    },
+    /// Open a new shell.
+    CreateShell,
+    /// Close a shell.
+    CloseShell {
+        /// ID of the shell.
+        id: Sid,
+    },
+    /// Stop all users from typing, or allow it again.
+    SetReadOnly {
+        /// Whether the session should be read-only.
+        read_only: bool,
+    },
+}
+
+/// Response from a running session to a request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    /// The request was carried out.
+    Ok,
+    /// Users connected to the session.
+    Users {
+        /// Users in the session.
+        users: Vec<User>,
+        /// Whether the session is read-only.
+        read_only: bool,
+    },
+    /// Links to the session.
+    Urls {
+        /// Link for viewing the session.
+        url: String,
+        /// Link for writing to the session, if it has read-only mode.
+        write_url: Option<String>,
+    },
+    /// The request failed.
+    Error {
+        /// Description of the error.
+        message: String,
+    },
+}
+
+/// Returns the directory that holds the control sockets of running sessions.
+pub fn socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("sshx"),
+        _ => std::env::temp_dir().join(format!("sshx-{}", nix::unistd::getuid())),
+    }
+}
+
+/// Returns the path of the control socket for a session.
+pub fn socket_path(session: &str) -> PathBuf {
+    socket_dir().join(format!("{session}.sock"))
+}
+
+/// Find the control socket of a running session.
+///
+/// Without a session name, this expects exactly one session to be running.
+/// Sockets left behind by sessions that did not exit cleanly are removed.
+pub fn find_socket(session: Option<&str>) -> Result<PathBuf> {
+    if let Some(session) = session {
+        let path = socket_path(session);
+        ensure!(is_live(&path), "no running session named {session}");
+        return Ok(path);
+    }
+    let mut sessions = Vec::new();
+    if let Ok(entries) = fs::read_dir(socket_dir()) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "sock") && is_live(&path) {
+                sessions.push(path);
+            }
+        }
+    }
+    match sessions.len() {
+        0 => bail!("no running sessions found"),
+        1 => Ok(sessions.remove(0)),
+        _ => {
+            let mut names: Vec<_> = sessions
+                .iter()
+                .filter_map(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+                .collect();
+            names.sort();
+            bail!(
+                "several sessions are running, choose one with --session: {}",
+                names.join(", ")
+            );
+        }
+    }
+}
+
+/// Check if a session is listening on a socket, removing it otherwise.
+fn is_live(path: &Path) -> bool {
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => true,
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+            fs::remove_file(path).ok();
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Send a request to the session listening on a control socket.
+pub async fn request(path: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("failed to connect to {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    let line = lines
+        .next_line()
+        .await?
+        .context("session closed the control socket")?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Control socket of a running session, removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ControlSocket {
+    /// Listen for requests on a socket at `path`, acting on the session through
+    /// its controller handle.
+    pub fn bind(path: &Path, handle: ControllerHandle) -> Result<Self> {
+        let dir = path.parent().context("socket path has no parent")?;
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        // The directory may be shared, so make sure no one else controls it.
+        let metadata = fs::metadata(dir)?;
+        ensure!(
+            metadata.uid() == nix::unistd::getuid().as_raw() && metadata.mode() & 0o077 == 0,
+            "{} must be private to the current user",
+            dir.display()
+        );
+
+        if path.exists() {
+            // Left over from a session that did not exit cleanly.
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_connection(stream, handle.clone()));
+                    }
+                    Err(err) => {
+                        warn!(?err, "failed to accept on control socket");
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            path: path.into(),
+            task,
+        })
+    }
+
+    /// Returns the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Answer requests on a single connection to the control socket.
+async fn serve_connection(stream: UnixStream, handle: ControllerHandle) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => {
+                debug!(?request, "control request");
+                match handle_request(&handle, request).await {
+                    Ok(response) => response,
+                    Err(err) => Response::Error {
+                        message: err.to_string(),
+                    },
+                }
+            }
+            Err(err) => Response::Error {
+                message: format!("invalid request: {err}"),
+            },
+        };
+        let mut line = serde_json::to_string(&response).unwrap();
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_request(handle: &ControllerHandle, request: Request) -> Result<Response> {
+    Ok(match request {
+        Request::Users => Response::Users {
+            users: handle.users(),
+            read_only: handle.read_only(),
+        },
+        Request::Urls => Response::Urls {
+            url: handle.url().into(),
+            write_url: handle.write_url().map(String::from),
+        },
+        Request::Kick { user_id } => {
+            handle.kick(user_id).await?;
+            Response::Ok
+        }
+        Request::CreateShell => {
+            handle.create_shell().await?;
+            Response::Ok
+        }
+        Request::CloseShell { id } => {
+            handle.close_shell(id).await?;
+            Response::Ok
+        }
+        Request::SetReadOnly { read_only } => {
+            handle.set_read_only(read_only).await?;
+            Response::Ok
+        }
+    })
+}
@@ -1,23 +1,33 @@
 //! Network gRPC client allowing server control of terminals.
 
 use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
+use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
 use sshx_core::proto::{
-    client_update::ClientMessage, server_update::ServerMessage,
-    sshx_service_client::SshxServiceClient, ClientUpdate, CloseRequest, NewShell, OpenRequest,
+    client_update::ClientMessage, exit_status::Status, server_update::ServerMessage, ClientUpdate,
+    CloseRequest, Compression, ExitStatus, ExitedShell, JoinResponse, NewShell, OpenRequest,
+    ShellPosition, UserList,
 };
-use sshx_core::{rand_alphanumeric, Sid};
-use tokio::sync::mpsc;
+use sshx_core::{rand_alphanumeric, Sid, Uid};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
 use tokio::task;
 use tokio::time::{self, Duration, Instant, MissedTickBehavior};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::transport::Channel;
+use tonic::Code;
 use tracing::{debug, error, warn};
 
 use crate::encrypt::Encrypt;
-use crate::runner::{Runner, ShellData};
+use crate::record::Recorder;
+use crate::runner::{Author, Runner, ShellData};
+use crate::terminal;
+use crate::transport::Transport;
 
 /// Interval for sending empty heartbeat messages to the server.
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
@@ -25,18 +35,558 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
 /// Interval to automatically reestablish connections.
 const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
 
+/// Reset the retry delay after a connection lasts this long.
+const RETRY_RESET: Duration = Duration::from_secs(10);
+
+/// Default time that shells have to exit after they are hung up, before they
+/// are killed.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Extra time to wait for shells to close after their grace period, since they
+/// are killed at the end of it.
+const CLOSE_MARGIN: Duration = Duration::from_secs(1);
+
+/// Changes in the connection to the server, reported while the controller runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The connection to the server was lost, and will be retried.
+    Disconnected {
+        /// Description of the error that ended the connection.
+        error: String,
+        /// Seconds to wait before reconnecting.
+        retry_secs: u64,
+    },
+    /// The connection was reestablished after being lost.
+    Reconnected,
+    /// The session was closed on the server.
+    Closed {
+        /// Why the session was closed.
+        reason: String,
+    },
+    /// The client stopped, but left the session open so it can be resumed.
+    LeftOpen {
+        /// Why the client stopped.
+        reason: String,
+    },
+}
+
+/// How the controller reconnects after losing its connection to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Delay before the first retry, which doubles after each failure.
+    pub initial_delay: Duration,
+    /// Longest delay between retries.
+    pub max_delay: Duration,
+    /// Give up after this many failures in a row, or never if unset.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(16),
+            max_retries: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after `retries` earlier failures in a row.
+    fn delay(&self, retries: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(retries.min(31));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+/// Settings of a controller that are chosen when it is built.
+#[derive(Debug, Clone)]
+struct Options {
+    heartbeat_interval: Duration,
+    reconnect_interval: Duration,
+    retry: RetryPolicy,
+    output_capacity: usize,
+    shell_capacity: usize,
+    grace_period: Duration,
+    output_rate: Option<u64>,
+    record_dir: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            reconnect_interval: RECONNECT_INTERVAL,
+            retry: RetryPolicy::default(),
+            output_capacity: 64,
+            shell_capacity: 16,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            output_rate: None,
+            record_dir: None,
+        }
+    }
+}
+
+/// A user waiting for the host to let them into the session.
+#[derive(Debug)]
+pub struct JoinRequest {
+    /// ID of the user in the session.
+    pub user_id: Uid,
+    /// Name that the user chose for themselves.
+    pub name: String,
+    output_tx: mpsc::Sender<ClientMessage>,
+}
+
+impl JoinRequest {
+    /// Let the user into the session, or turn them away.
+    pub async fn answer(self, approved: bool) -> Result<()> {
+        let response = JoinResponse {
+            user_id: self.user_id.0,
+            approved,
+        };
+        self.output_tx
+            .send(ClientMessage::JoinResponse(response))
+            .await
+            .context("controller has stopped")
+    }
+}
+
+/// A user connected to the session, as reported by the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    /// ID of the user in the session.
+    pub id: Uid,
+    /// Display name of the user.
+    pub name: String,
+    /// Whether the user can currently type into shells.
+    pub can_write: bool,
+}
+
+/// Handle for managing a running session from other tasks.
+///
+/// Requests are sent to the server by the controller, after it reconnects if
+/// the connection is currently lost.
+#[derive(Debug, Clone)]
+pub struct ControllerHandle {
+    url: String,
+    write_url: Option<String>,
+    users_rx: watch::Receiver<UserList>,
+    output_tx: mpsc::Sender<ClientMessage>,
+    stop: Arc<Notify>,
+}
+
+impl ControllerHandle {
+    /// Returns the URL of the session.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the write URL of the session, if it has one.
+    pub fn write_url(&self) -> Option<&str> {
+        self.write_url.as_deref()
+    }
+
+    /// Returns the users in the session, as last reported by the server.
+    pub fn users(&self) -> Vec<User> {
+        let list = self.users_rx.borrow();
+        list.users
+            .iter()
+            .map(|user| User {
+                id: Uid(user.id),
+                name: user.name.clone(),
+                can_write: user.can_write,
+            })
+            .collect()
+    }
+
+    /// Returns whether all users are currently stopped from typing.
+    pub fn read_only(&self) -> bool {
+        self.users_rx.borrow().read_only
+    }
+
+    /// Stop the controller, so that [`Controller::run`] returns.
+    ///
+    /// If the controller is not running, the next call to `run` returns right
+    /// away instead.
+    pub fn stop(&self) {
+        self.stop.notify_one();
+    }
+
+    /// Disconnect a user from the session. They can rejoin with the link.
+    pub async fn kick(&self, id: Uid) -> Result<()> {
+        let exists = self.users_rx.borrow().users.iter().any(|u| u.id == id.0);
+        ensure!(exists, "there is no user with ID {id}");
+        self.send(ClientMessage::KickUser(id.0)).await
+    }
+
+    /// Stop all users from typing into shells, or allow it again for the
+    /// users who were able to before.
+    pub async fn set_read_only(&self, read_only: bool) -> Result<()> {
+        self.send(ClientMessage::SetReadOnly(read_only)).await
+    }
+
+    /// Open a new shell in the session.
+    pub async fn create_shell(&self) -> Result<()> {
+        let position = ShellPosition { x: 0, y: 0 };
+        self.send(ClientMessage::CreateShell(position)).await
+    }
+
+    /// Close a shell in the session.
+    pub async fn close_shell(&self, id: Sid) -> Result<()> {
+        self.send(ClientMessage::CloseShell(id.0)).await
+    }
+
+    async fn send(&self, msg: ClientMessage) -> Result<()> {
+        self.output_tx
+            .send(msg)
+            .await
+            .context("controller has stopped")
+    }
+}
+
+/// Secrets needed to reattach to an existing session, such as after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials {
+    /// Address of the server that the session was opened on.
+    pub origin: String,
+    /// Name of the session.
+    pub name: String,
+    /// Signed verification token for the session.
+    pub token: String,
+    /// Public web URL of the session, without the encryption key.
+    pub url: String,
+    /// Encryption key for the session, hidden from the server.
+    pub encryption_key: String,
+    /// Password for write access, if read-only mode is enabled.
+    pub write_password: Option<String>,
+    /// Whether the server accepted compressed terminal output.
+    #[serde(default)]
+    pub compress: bool,
+}
+
+impl Credentials {
+    /// Read credentials that were previously saved to a file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Save the credentials to a file that only the current user can read.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Builder for a controller, with options for programs that embed sshx.
+///
+/// Options that are not set have the same defaults as [`Controller::new`].
+/// Events and users can be observed with [`Controller::subscribe_events`] and
+/// [`Controller::handle`] once the controller is built, before it runs.
+#[derive(Debug, Clone)]
+pub struct ControllerBuilder {
+    transport: Transport,
+    runner: Runner,
+    name: String,
+    enable_readers: bool,
+    require_approval: bool,
+    compress: bool,
+    encryption_key: Option<String>,
+    write_password: Option<String>,
+    options: Options,
+}
+
+impl ControllerBuilder {
+    /// Create a builder for a controller that connects with a transport.
+    pub fn new(transport: Transport, runner: Runner) -> Self {
+        Self {
+            transport,
+            runner,
+            name: String::new(),
+            enable_readers: false,
+            require_approval: false,
+            compress: false,
+            encryption_key: None,
+            write_password: None,
+            options: Options::default(),
+        }
+    }
+
+    /// Set the name of the session displayed in the title.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Generate separate URLs for viewers and editors of the session.
+    pub fn enable_readers(mut self, enable: bool) -> Self {
+        self.enable_readers = enable;
+        self
+    }
+
+    /// Require users to be let in through [`Controller::join_requests`].
+    pub fn require_approval(mut self, require: bool) -> Self {
+        self.require_approval = require;
+        self
+    }
+
+    /// Ask the server to accept compressed terminal output, which saves
+    /// bandwidth. The size of each message then depends on what the output
+    /// contains, which an observer of the connection could learn from.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Use this encryption key instead of a random one.
+    pub fn encryption_key(mut self, key: &str) -> Self {
+        self.encryption_key = Some(key.into());
+        self
+    }
+
+    /// Use this write password instead of a random one, which also enables
+    /// separate URLs for viewers and editors.
+    pub fn write_password(mut self, password: &str) -> Self {
+        self.write_password = Some(password.into());
+        self.enable_readers = true;
+        self
+    }
+
+    /// Set how long shells have to exit after they are hung up.
+    pub fn grace_period(mut self, grace: Duration) -> Self {
+        self.options.grace_period = grace;
+        self
+    }
+
+    /// Limit the output of every shell to `rate` bytes per second, which is
+    /// raised to at least [`MIN_RATE`](crate::governor::MIN_RATE).
+    pub fn output_rate(mut self, rate: u64) -> Self {
+        self.options.output_rate = Some(rate);
+        self
+    }
+
+    /// Record every shell to an asciicast file in `dir`.
+    pub fn record_to(mut self, dir: &Path) -> Self {
+        self.options.record_dir = Some(dir.into());
+        self
+    }
+
+    /// Set the interval for sending heartbeat messages to the server.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.options.heartbeat_interval = interval;
+        self
+    }
+
+    /// Set the interval for reestablishing the connection to the server.
+    pub fn reconnect_interval(mut self, interval: Duration) -> Self {
+        self.options.reconnect_interval = interval;
+        self
+    }
+
+    /// Set how to reconnect after losing the connection to the server.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Set the number of messages to the server that can be buffered.
+    pub fn output_capacity(mut self, capacity: usize) -> Self {
+        self.options.output_capacity = capacity.max(1);
+        self
+    }
+
+    /// Set the number of messages to each shell that can be buffered.
+    pub fn shell_capacity(mut self, capacity: usize) -> Self {
+        self.options.shell_capacity = capacity.max(1);
+        self
+    }
+
+    /// Open a new session on the server.
+    pub async fn build(self) -> Result<Controller> {
+        let origin = self.transport.origin();
+        debug!(%origin, "connecting to server");
+        let encryption_key = match self.encryption_key {
+            Some(key) => key,
+            None => rand_alphanumeric(14), // 83.3 bits of entropy
+        };
+        check_secret(&encryption_key).context("invalid encryption key")?;
+
+        let kdf_task = {
+            let encryption_key = encryption_key.clone();
+            task::spawn_blocking(move || Encrypt::new(&encryption_key))
+        };
+
+        let write_password = match self.write_password {
+            Some(password) => Some(password),
+            None if self.enable_readers => Some(rand_alphanumeric(14)), // 83.3 bits of entropy
+            None => None,
+        };
+        if let Some(password) = &write_password {
+            check_secret(password).context("invalid write password")?;
+        }
+        let kdf_write_password_task = write_password
+            .clone()
+            .map(|write_password| task::spawn_blocking(move || Encrypt::new(&write_password)));
+
+        let mut client = self.transport.connect().await?;
+        let encrypt = kdf_task.await?;
+        let write_password_hash = if let Some(task) = kdf_write_password_task {
+            Some(task.await?.zeros().into())
+        } else {
+            None
+        };
+
+        let req = OpenRequest {
+            origin: origin.into(),
+            encrypted_zeros: encrypt.zeros().into(),
+            name: self.name,
+            write_password_hash,
+            require_approval: self.require_approval,
+            compression: if self.compress {
+                vec![Compression::Deflate.into()]
+            } else {
+                Vec::new()
+            },
+        };
+        let resp = client.open(req).await?.into_inner();
+        let compress = resp.compression() == Compression::Deflate;
+
+        let credentials = Credentials {
+            origin: origin.into(),
+            name: resp.name,
+            token: resp.token,
+            url: resp.url,
+            encryption_key,
+            write_password,
+            compress,
+        };
+        let controller = Controller::from_credentials(
+            self.transport,
+            credentials,
+            self.runner,
+            encrypt,
+            self.options,
+        );
+        if let Runner::Tmux { session } = &controller.runner {
+            session.announce_windows(controller.output_tx.clone());
+        }
+        if controller.runner.is_single_shell() {
+            // Nobody else can type or open shells, so open the only one now.
+            let position = ShellPosition { x: 0, y: 0 };
+            controller
+                .output_tx
+                .try_send(ClientMessage::SetReadOnly(true))?;
+            controller
+                .output_tx
+                .try_send(ClientMessage::CreateShell(position))?;
+        }
+        Ok(controller)
+    }
+
+    /// Reattach to an existing session on the server, restarting its shells.
+    ///
+    /// The transport must be for the origin in the credentials, whose keys are
+    /// used instead of any set on the builder. Returns `None` if the server no
+    /// longer knows about the session.
+    pub async fn resume(self, credentials: Credentials) -> Result<Option<Controller>> {
+        ensure!(
+            self.transport.origin() == credentials.origin,
+            "transport does not match the origin of the session"
+        );
+        debug!(origin = %credentials.origin, name = %credentials.name, "resuming session");
+        let kdf_task = {
+            let encryption_key = credentials.encryption_key.clone();
+            task::spawn_blocking(move || Encrypt::new(&encryption_key))
+        };
+
+        // Check that the session still exists by sending a single hello message.
+        let mut client = self.transport.connect().await?;
+        let hello = ClientUpdate {
+            client_message: Some(ClientMessage::Hello(format!(
+                "{},{}",
+                credentials.name, credentials.token
+            ))),
+        };
+        match client.channel(tokio_stream::once(hello)).await {
+            Ok(_) => {}
+            Err(status) if matches!(status.code(), Code::NotFound | Code::Unauthenticated) => {
+                debug!(?status, "session cannot be resumed");
+                return Ok(None);
+            }
+            Err(status) => return Err(status.into()),
+        }
+
+        let encrypt = kdf_task.await?;
+        let mut controller = Controller::from_credentials(
+            self.transport,
+            credentials,
+            self.runner,
+            encrypt,
+            self.options,
+        );
+        controller.restore_shells = true;
+        Ok(Some(controller))
+    }
+}
+
+/// Check that a key or password can be put in the fragment of a session URL,
+/// after the key and separated by a comma.
+fn check_secret(secret: &str) -> Result<()> {
+    ensure!(!secret.is_empty(), "must not be empty");
+    ensure!(
+        secret.chars().all(|c| c.is_ascii_alphanumeric()),
+        "must only contain ASCII letters and digits"
+    );
+    Ok(())
+}
+
 /// Handles a single session's communication with the remote server.
 pub struct Controller {
-    origin: String,
+    transport: Transport,
     runner: Runner,
     encrypt: Encrypt,
     encryption_key: String,
+    write_password: Option<String>,
+    /// Whether terminal output is compressed before it is encrypted.
+    compress: bool,
+    /// Timing, buffering and recording of the session and its shells.
+    options: Options,
 
     name: String,
     token: String,
+    base_url: String,
     url: String,
     write_url: Option<String>,
 
+    /// Set after resuming a session, until the server reports its open shells.
+    restore_shells: bool,
+    /// Set once a shell has been opened, if the runner only allows one.
+    single_shell_opened: bool,
+
+    /// Notified when the shell mirrored in the local terminal exits.
+    mirror_done: Option<oneshot::Sender<()>>,
+    /// Shell that is mirrored in the local terminal, whose size it controls.
+    mirrored: Option<Sid>,
+    /// Receives users waiting to join, who are turned away if this is unset.
+    join_tx: Option<mpsc::Sender<JoinRequest>>,
+
+    /// Time of the last input to or output from any shell.
+    activity_tx: watch::Sender<Instant>,
+    /// Broadcasts changes in the connection to the server.
+    events_tx: broadcast::Sender<Event>,
+    /// Users in the session, as last reported by the server.
+    users_tx: watch::Sender<UserList>,
+    /// Notified to stop the controller from running.
+    stop: Arc<Notify>,
+
     /// Channels with backpressure routing messages to each shell task.
     shells_tx: HashMap<Sid, mpsc::Sender<ShellData>>,
     /// Channel shared with tasks to allow them to output client messages.
@@ -47,77 +597,103 @@ pub struct Controller {
 
 impl Controller {
     /// Construct a new controller, connecting to the remote server.
+    ///
+    /// This uses the proxy from the environment, if one is configured.
     pub async fn new(
         origin: &str,
         name: &str,
         runner: Runner,
         enable_readers: bool,
     ) -> Result<Self> {
-        debug!(%origin, "connecting to server");
-        let encryption_key = rand_alphanumeric(14); // 83.3 bits of entropy
+        let transport = Transport::new(origin)?;
+        Self::new_with_transport(transport, name, runner, enable_readers, false).await
+    }
 
-        let kdf_task = {
-            let encryption_key = encryption_key.clone();
-            task::spawn_blocking(move || Encrypt::new(&encryption_key))
-        };
+    /// Construct a new controller, connecting to the server with a transport.
+    ///
+    /// If `require_approval` is set, users must be let in by the host through
+    /// [`Controller::join_requests`] before they can join the session.
+    pub async fn new_with_transport(
+        transport: Transport,
+        name: &str,
+        runner: Runner,
+        enable_readers: bool,
+        require_approval: bool,
+    ) -> Result<Self> {
+        ControllerBuilder::new(transport, runner)
+            .name(name)
+            .enable_readers(enable_readers)
+            .require_approval(require_approval)
+            .build()
+            .await
+    }
 
-        let (write_password, kdf_write_password_task) = if enable_readers {
-            let write_password = rand_alphanumeric(14); // 83.3 bits of entropy
-            let task = {
-                let write_password = write_password.clone();
-                task::spawn_blocking(move || Encrypt::new(&write_password))
-            };
-            (Some(write_password), Some(task))
-        } else {
-            (None, None)
-        };
+    /// Returns a builder for a controller with more options.
+    pub fn builder(transport: Transport, runner: Runner) -> ControllerBuilder {
+        ControllerBuilder::new(transport, runner)
+    }
 
-        let mut client = Self::connect(origin).await?;
-        let encrypt = kdf_task.await?;
-        let write_password_hash = if let Some(task) = kdf_write_password_task {
-            Some(task.await?.zeros().into())
-        } else {
-            None
-        };
+    /// Reattach to an existing session on the server, restarting its shells.
+    ///
+    /// Returns `None` if the server no longer knows about the session, for
+    /// instance because it was closed or expired.
+    pub async fn resume(credentials: Credentials, runner: Runner) -> Result<Option<Self>> {
+        let transport = Transport::new(&credentials.origin)?;
+        Self::resume_with_transport(transport, credentials, runner).await
+    }
 
-        let req = OpenRequest {
-            origin: origin.into(),
-            encrypted_zeros: encrypt.zeros().into(),
-            name: name.into(),
-            write_password_hash,
-        };
-        let mut resp = client.open(req).await?.into_inner();
-        resp.url = resp.url + "#" + &encryption_key;
+    /// Reattach to an existing session, connecting to the server with a
+    /// transport for the origin in the credentials.
+    pub async fn resume_with_transport(
+        transport: Transport,
+        credentials: Credentials,
+        runner: Runner,
+    ) -> Result<Option<Self>> {
+        ControllerBuilder::new(transport, runner)
+            .resume(credentials)
+            .await
+    }
 
-        let write_url = if let Some(write_password) = write_password {
-            Some(resp.url.clone() + "," + &write_password)
-        } else {
-            None
-        };
+    fn from_credentials(
+        transport: Transport,
+        credentials: Credentials,
+        runner: Runner,
+        encrypt: Encrypt,
+        options: Options,
+    ) -> Self {
+        let url = credentials.url.clone() + "#" + &credentials.encryption_key;
+        let write_url = credentials
+            .write_password
+            .as_ref()
+            .map(|write_password| url.clone() + "," + write_password);
 
-        let (output_tx, output_rx) = mpsc::channel(64);
-        Ok(Self {
-            origin: origin.into(),
+        let (output_tx, output_rx) = mpsc::channel(options.output_capacity);
+        Self {
+            transport,
             runner,
             encrypt,
-            encryption_key,
-            name: resp.name,
-            token: resp.token,
-            url: resp.url,
+            encryption_key: credentials.encryption_key,
+            write_password: credentials.write_password,
+            compress: credentials.compress,
+            options,
+            name: credentials.name,
+            token: credentials.token,
+            base_url: credentials.url,
+            url,
             write_url,
+            restore_shells: false,
+            single_shell_opened: false,
+            mirror_done: None,
+            mirrored: None,
+            join_tx: None,
+            activity_tx: watch::Sender::new(Instant::now()),
+            events_tx: broadcast::Sender::new(16),
+            users_tx: watch::Sender::new(UserList::default()),
+            stop: Arc::new(Notify::new()),
             shells_tx: HashMap::new(),
             output_tx,
             output_rx,
-        })
-    }
-
-    /// Create a new gRPC client to the HTTP(S) origin.
-    ///
-    /// This is used on reconnection to the server, since some replicas may be
-    /// gracefully shutting down, which means connected clients need to start a
-    /// new TCP handshake.
-    async fn connect(origin: &str) -> Result<SshxServiceClient<Channel>, tonic::transport::Error> {
-        SshxServiceClient::connect(String::from(origin)).await
+        }
     }
 
     /// Returns the name of the session.
@@ -140,18 +716,134 @@ impl Controller {
         &self.encryption_key
     }
 
-    /// Run the controller forever, listening for requests from the server.
-    pub async fn run(&mut self) -> ! {
+    /// Mirror the next shell that is opened in the local terminal.
+    ///
+    /// The host can then type into that shell alongside web users. Returns a
+    /// receiver that resolves when the mirrored shell exits.
+    #[cfg(unix)]
+    pub fn mirror_next_shell(&mut self) -> oneshot::Receiver<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.mirror_done = Some(done_tx);
+        done_rx
+    }
+
+    /// Open shells at these positions right away, instead of waiting for users
+    /// to create them, so that no output is missed.
+    ///
+    /// The client picks their IDs, and the server reserves them so that shells
+    /// created later by users do not collide.
+    pub fn open_shells(&mut self, positions: &[(i32, i32)]) {
+        let mut next_id = 1;
+        for &center in positions {
+            while self.shells_tx.contains_key(&Sid(next_id)) {
+                next_id += 1;
+            }
+            self.spawn_shell_task(Sid(next_id), Some(center), 0);
+        }
+    }
+
+    /// Record every shell opened from now on to an asciicast file in `dir`.
+    pub fn record_to(&mut self, dir: &Path) {
+        self.options.record_dir = Some(dir.into());
+    }
+
+    /// Set how long shells have to exit after they are hung up, when a user
+    /// closes them or the session is closed, before they are killed.
+    pub fn set_grace_period(&mut self, grace: Duration) {
+        self.options.grace_period = grace;
+    }
+
+    /// Limit the output of every shell opened from now on to `rate` bytes per
+    /// second. Output beyond that is skipped, keeping the latest screen.
+    ///
+    /// Rates below [`MIN_RATE`](crate::governor::MIN_RATE) are raised to it.
+    pub fn limit_output_rate(&mut self, rate: u64) {
+        self.options.output_rate = Some(rate);
+    }
+
+    /// Returns a handle for managing the session while the controller runs.
+    pub fn handle(&self) -> ControllerHandle {
+        ControllerHandle {
+            url: self.url.clone(),
+            write_url: self.write_url.clone(),
+            users_rx: self.users_tx.subscribe(),
+            output_tx: self.output_tx.clone(),
+            stop: Arc::clone(&self.stop),
+        }
+    }
+
+    /// Receive users waiting to join, if the session requires approval.
+    ///
+    /// Each request should be answered, or the user keeps waiting. Requests
+    /// that arrive when the receiver is full or dropped are denied.
+    pub fn join_requests(&mut self) -> mpsc::Receiver<JoinRequest> {
+        let (join_tx, join_rx) = mpsc::channel(16);
+        self.join_tx = Some(join_tx);
+        join_rx
+    }
+
+    /// Returns the credentials needed to resume this session later.
+    pub fn credentials(&self) -> Credentials {
+        Credentials {
+            origin: self.transport.origin().into(),
+            name: self.name.clone(),
+            token: self.token.clone(),
+            url: self.base_url.clone(),
+            encryption_key: self.encryption_key.clone(),
+            write_password: self.write_password.clone(),
+            compress: self.compress,
+        }
+    }
+
+    /// Watch the time of the last remote input to or output from any shell.
+    pub fn activity(&self) -> watch::Receiver<Instant> {
+        self.activity_tx.subscribe()
+    }
+
+    /// Subscribe to changes in the connection to the server.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.events_tx.subscribe()
+    }
+
+    /// Run the controller, listening for requests from the server.
+    ///
+    /// The connection is reestablished whenever it is lost. This returns once
+    /// the controller is stopped through [`ControllerHandle::stop`], or with
+    /// the last error if the retry policy gives up. The session is left
+    /// open on the server either way, so it can be run again or closed.
+    pub async fn run(&mut self) -> Result<()> {
+        let stop = Arc::clone(&self.stop);
+        let retry = self.options.retry;
         let mut last_retry = Instant::now();
         let mut retries = 0;
+        let mut connected = true;
         loop {
-            if let Err(err) = self.try_channel().await {
-                if last_retry.elapsed() >= Duration::from_secs(10) {
+            let result = tokio::select! {
+                result = self.try_channel(&mut connected) => result,
+                _ = stop.notified() => return Ok(()),
+            };
+            if let Err(err) = result {
+                if last_retry.elapsed() >= RETRY_RESET {
                     retries = 0;
                 }
-                let secs = 2_u64.pow(retries.min(4));
+                if retry.max_retries.is_some_and(|max| retries >= max) {
+                    error!(%err, "disconnected, giving up after {retries} retries");
+                    return Err(err);
+                }
+                let delay = retry.delay(retries);
+                let secs = delay.as_secs();
                 error!(%err, "disconnected, retrying in {secs}s...");
-                time::sleep(Duration::from_secs(secs)).await;
+                self.events_tx
+                    .send(Event::Disconnected {
+                        error: err.to_string(),
+                        retry_secs: secs,
+                    })
+                    .ok();
+                connected = false;
+                tokio::select! {
+                    _ = time::sleep(delay) => {}
+                    _ = stop.notified() => return Ok(()),
+                }
                 retries += 1;
             }
             last_retry = Instant::now();
@@ -159,19 +851,25 @@ impl Controller {
     }
 
     /// Helper function used by `run()` that can return errors.
-    async fn try_channel(&mut self) -> Result<()> {
+    ///
+    /// The `connected` flag tracks whether the last attempt succeeded, so that
+    /// only reconnections after a failure are reported.
+    async fn try_channel(&mut self, connected: &mut bool) -> Result<()> {
         let (tx, rx) = mpsc::channel(16);
 
         let hello = ClientMessage::Hello(format!("{},{}", self.name, self.token));
         send_msg(&tx, hello).await?;
 
-        let mut client = Self::connect(&self.origin).await?;
+        let mut client = self.transport.connect().await?;
         let resp = client.channel(ReceiverStream::new(rx)).await?;
         let mut messages = resp.into_inner(); // A stream of server messages.
+        if !std::mem::replace(connected, true) {
+            self.events_tx.send(Event::Reconnected).ok();
+        }
 
-        let mut interval = time::interval(HEARTBEAT_INTERVAL);
+        let mut interval = time::interval(self.options.heartbeat_interval);
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-        let mut reconnect = pin!(time::sleep(RECONNECT_INTERVAL));
+        let mut reconnect = pin!(time::sleep(self.options.reconnect_interval));
         loop {
             let message = tokio::select! {
                 _ = interval.tick() => {
@@ -180,6 +878,9 @@ impl Controller {
                 }
                 msg = self.output_rx.recv() => {
                     let msg = msg.context("unreachable: output_tx was closed?")?;
+                    if let ClientMessage::Data(_) = msg {
+                        self.activity_tx.send_replace(Instant::now());
+                    }
                     send_msg(&tx, msg).await?;
                     continue;
                 }
@@ -194,11 +895,17 @@ impl Controller {
             };
 
             match message {
+                ServerMessage::Input(input) if self.runner.is_single_shell() => {
+                    warn!(%input.id, "received input for a read-only shell");
+                }
                 ServerMessage::Input(input) => {
+                    self.activity_tx.send_replace(Instant::now());
                     let data = self.encrypt.segment(0x200000000, input.offset, &input.data);
+                    let author = (input.user_id != 0)
+                        .then(|| Author::new(Uid(input.user_id), input.user_name));
                     if let Some(sender) = self.shells_tx.get(&Sid(input.id)) {
                         // This line applies backpressure if the shell task is overloaded.
-                        sender.send(ShellData::Data(data)).await.ok();
+                        sender.send(ShellData::Data(data, author)).await.ok();
                     } else {
                         warn!(%input.id, "received data for non-existing shell");
                     }
@@ -206,19 +913,34 @@ impl Controller {
                 ServerMessage::CreateShell(new_shell) => {
                     let id = Sid(new_shell.id);
                     let center = (new_shell.x, new_shell.y);
-                    if !self.shells_tx.contains_key(&id) {
-                        self.spawn_shell_task(id, center);
+                    if self.single_shell_opened {
+                        warn!(%id, "server asked to create a shell, but only one is allowed");
+                        let err = String::from("this session cannot open more shells");
+                        send_msg(&tx, ClientMessage::Error(err)).await?;
+                    } else if !self.shells_tx.contains_key(&id) {
+                        self.single_shell_opened = self.runner.is_single_shell();
+                        self.spawn_shell_task(id, Some(center), 0);
                     } else {
                         warn!(%id, "server asked to create duplicate shell");
                     }
                 }
                 ServerMessage::CloseShell(id) => {
                     // Closes the channel when it is dropped, notifying the task to shut down.
-                    self.shells_tx.remove(&Sid(id));
+                    if let Some(sender) = self.shells_tx.remove(&Sid(id)) {
+                        sender
+                            .send(ShellData::Close(self.options.grace_period))
+                            .await
+                            .ok();
+                    }
                     send_msg(&tx, ClientMessage::ClosedShell(id)).await?;
                 }
                 ServerMessage::Sync(seqnums) => {
+                    let restore_shells = std::mem::take(&mut self.restore_shells);
                     for (id, seq) in seqnums.map {
+                        if restore_shells && !self.shells_tx.contains_key(&Sid(id)) {
+                            debug!(%id, seq, "restoring shell from resumed session");
+                            self.spawn_shell_task(Sid(id), None, seq);
+                        }
                         if let Some(sender) = self.shells_tx.get(&Sid(id)) {
                             sender.send(ShellData::Sync(seq)).await.ok();
                         } else {
@@ -228,12 +950,39 @@ impl Controller {
                     }
                 }
                 ServerMessage::Resize(msg) => {
+                    if self.mirrored == Some(Sid(msg.id)) {
+                        // The size of a mirrored shell follows the local terminal.
+                        continue;
+                    }
                     if let Some(sender) = self.shells_tx.get(&Sid(msg.id)) {
                         sender.send(ShellData::Size(msg.rows, msg.cols)).await.ok();
                     } else {
                         warn!(%msg.id, "received resize for non-existing shell");
                     }
                 }
+                ServerMessage::Users(list) => {
+                    self.users_tx.send_replace(list);
+                }
+                ServerMessage::JoinRequest(request) => {
+                    let user_id = Uid(request.user_id);
+                    let request = JoinRequest {
+                        user_id,
+                        name: request.name,
+                        output_tx: self.output_tx.clone(),
+                    };
+                    let sent = match &self.join_tx {
+                        Some(join_tx) => join_tx.try_send(request).map_err(|err| err.into_inner()),
+                        None => Err(request),
+                    };
+                    if sent.is_err() {
+                        warn!(%user_id, "no one is answering join requests, denying");
+                        let response = JoinResponse {
+                            user_id: user_id.0,
+                            approved: false,
+                        };
+                        send_msg(&tx, ClientMessage::JoinResponse(response)).await?;
+                    }
+                }
                 ServerMessage::Ping(ts) => {
                     // Echo back the timestamp, for stateless latency measurement.
                     send_msg(&tx, ClientMessage::Pong(ts)).await?;
@@ -246,41 +995,105 @@ impl Controller {
     }
 
     /// Entry point to start a new terminal task on the client.
-    fn spawn_shell_task(&mut self, id: Sid, center: (i32, i32)) {
-        let (shell_tx, shell_rx) = mpsc::channel(16);
+    ///
+    /// The `center` is only given for new shells, which are announced to the
+    /// server. Restored shells already exist there, so their output continues
+    /// from the server's sequence number `seq`.
+    fn spawn_shell_task(&mut self, id: Sid, center: Option<(i32, i32)>, seq: u64) {
+        let (shell_tx, shell_rx) = mpsc::channel(self.options.shell_capacity);
+        #[cfg(unix)]
+        if let Some(done_tx) = self.mirror_done.take() {
+            let (mirror_tx, mirror_rx) = mpsc::channel(64);
+            // The channel is empty, so this is the first message the task sees.
+            shell_tx.try_send(ShellData::Mirror(mirror_tx)).ok();
+            let weak_tx = shell_tx.downgrade();
+            tokio::spawn(crate::console::mirror(weak_tx, mirror_rx, done_tx));
+            self.mirrored = Some(id);
+        }
+        if let Some(dir) = &self.options.record_dir {
+            match Recorder::create(dir, &self.name, id) {
+                Ok(recorder) => {
+                    debug!(%id, path = %recorder.path().display(), "recording shell");
+                    shell_tx.try_send(ShellData::Record(recorder)).ok();
+                }
+                Err(err) => error!(%id, ?err, "failed to create recording"),
+            }
+        }
+        if let Some(rate) = self.options.output_rate {
+            shell_tx.try_send(ShellData::RateLimit(rate)).ok();
+        }
         let opt = self.shells_tx.insert(id, shell_tx);
         debug_assert!(opt.is_none(), "shell ID cannot be in existing tasks");
 
         let runner = self.runner.clone();
         let encrypt = self.encrypt.clone();
+        let compress = self.compress;
         let output_tx = self.output_tx.clone();
         tokio::spawn(async move {
             debug!(%id, "spawning new shell");
-            let new_shell = NewShell {
-                id: id.0,
-                x: center.0,
-                y: center.1,
-            };
-            if let Err(err) = output_tx.send(ClientMessage::CreatedShell(new_shell)).await {
-                error!(%id, ?err, "failed to send shell creation message");
-                return;
+            if let Some((x, y)) = center {
+                let new_shell = NewShell { id: id.0, x, y };
+                if let Err(err) = output_tx.send(ClientMessage::CreatedShell(new_shell)).await {
+                    error!(%id, ?err, "failed to send shell creation message");
+                    return;
+                }
             }
-            if let Err(err) = runner.run(id, encrypt, shell_rx, output_tx.clone()).await {
-                let err = ClientMessage::Error(err.to_string());
-                output_tx.send(err).await.ok();
+            match runner
+                .run(id, encrypt, compress, seq, shell_rx, output_tx.clone())
+                .await
+            {
+                Ok(Some(status)) => {
+                    // Viewers keep the shell's output and see how it ended.
+                    let status = match status {
+                        terminal::ExitStatus::Code(code) => Status::Code(code),
+                        terminal::ExitStatus::Signal(signal) => Status::Signal(signal),
+                    };
+                    let exited = ExitedShell {
+                        id: id.0,
+                        status: Some(ExitStatus {
+                            status: Some(status),
+                        }),
+                    };
+                    output_tx
+                        .send(ClientMessage::ExitedShell(exited))
+                        .await
+                        .ok();
+                    return;
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    let err = ClientMessage::Error(err.to_string());
+                    output_tx.send(err).await.ok();
+                }
             }
             output_tx.send(ClientMessage::ClosedShell(id.0)).await.ok();
         });
     }
 
     /// Terminate this session gracefully.
+    ///
+    /// Shells are hung up and given the grace period to exit before they are
+    /// killed, and then the session is closed on the server.
     pub async fn close(&self) -> Result<()> {
-        debug!("closing session");
+        debug!(shells = self.shells_tx.len(), "closing session");
+        let grace = self.options.grace_period;
+        let closing = self.shells_tx.values().map(|shell_tx| async move {
+            if shell_tx.send(ShellData::Close(grace)).await.is_ok() {
+                // The receiver is dropped once the shell task has finished.
+                shell_tx.closed().await;
+            }
+        });
+        // Shell tasks may be stuck sending output, which is no longer read.
+        let closing = time::timeout(grace + CLOSE_MARGIN, join_all(closing));
+        if closing.await.is_err() {
+            warn!("some shells did not close in time");
+        }
+
         let req = CloseRequest {
             name: self.name.clone(),
             token: self.token.clone(),
         };
-        let mut client = Self::connect(&self.origin).await?;
+        let mut client = self.transport.connect().await?;
         client.close(req).await?;
         Ok(())
     }
@@ -0,0 +1,137 @@
+//! Limits on the rate of output that a shell sends to the server.
+//!
+//! Output within the budget is sent as usual, in a burst of up to one second's
+//! worth. When a shell falls more than that far behind, the output in between
+//! is skipped and replaced by a marker, keeping only the latest screen.
+
+use tokio::time::{Duration, Instant};
+
+/// Keep at most this many bytes of the latest output when skipping.
+const KEEP_BYTES: usize = 1 << 14; // 16 KiB
+
+/// Lowest rate that a governor allows, in bytes per second.
+///
+/// At this rate, the marker for skipped output and half of the budget of kept
+/// output still fit in the budget, so the marker is not skipped itself.
+pub const MIN_RATE: u64 = 1 << 10; // 1 KiB
+
+/// Interval for sending output that was held back to stay within the budget.
+pub const THROTTLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Budget of output bytes per second for a single shell.
+#[derive(Debug, Clone)]
+pub struct Governor {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Governor {
+    /// Create a governor that allows `rate` bytes of output per second, and
+    /// at least [`MIN_RATE`].
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(MIN_RATE);
+        Self {
+            rate,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Returns the number of bytes that can be sent right now.
+    pub fn available(&mut self, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+        self.tokens as usize
+    }
+
+    /// Spend part of the budget on bytes that were sent.
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens = (self.tokens - bytes as f64).max(0.0);
+    }
+
+    /// Skip output after `start` in the content if more of it is waiting than
+    /// the budget allows in a second, keeping the latest screen.
+    ///
+    /// A marker is left in place of the skipped output. Output before `start`
+    /// is never changed, so it must include everything that was already sent.
+    /// Returns the number of bytes that were skipped.
+    pub fn coalesce(&self, content: &mut String, start: usize) -> usize {
+        if content.len() - start <= self.rate as usize {
+            return 0;
+        }
+
+        // Start at a new line, so that the kept output is not cut mid-sequence.
+        let keep = KEEP_BYTES.min(self.rate as usize / 2);
+        let mut cut = content.len() - keep;
+        while !content.is_char_boundary(cut) {
+            cut += 1;
+        }
+        if let Some(i) = content[cut..].find('\n') {
+            cut += i + 1;
+        }
+        if cut <= start {
+            return 0;
+        }
+
+        let skipped = cut - start;
+        let marker = format!("\r\n[sshx: output throttled, skipped {skipped} bytes]\r\n");
+        content.replace_range(start..cut, &marker);
+        skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{Duration, Instant};
+
+    use super::Governor;
+
+    #[test]
+    fn refill_budget() {
+        let mut governor = Governor::new(2000);
+        let start = Instant::now();
+        assert_eq!(governor.available(start), 2000);
+        governor.consume(2000);
+        assert_eq!(governor.available(start), 0);
+        let later = start + Duration::from_millis(500);
+        assert!((999..=1000).contains(&governor.available(later)));
+        let much_later = start + Duration::from_secs(10);
+        assert_eq!(governor.available(much_later), 2000);
+    }
+
+    #[test]
+    fn coalesce_backlog() {
+        let governor = Governor::new(2000);
+        let mut content = String::from("sent\r\n");
+        content.push_str(&"line\r\n".repeat(1000));
+        let skipped = governor.coalesce(&mut content, 6);
+        assert!(skipped > 0);
+        assert!(content.starts_with("sent\r\n\r\n[sshx: output throttled"));
+        assert!(content.contains(&format!("skipped {skipped} bytes]\r\nline\r\n")));
+        assert!(content.len() <= 6 + 1000 + 64);
+    }
+
+    #[test]
+    fn coalesce_tiny_rate() {
+        let governor = Governor::new(10);
+        let mut content = "line\r\n".repeat(1000);
+        assert!(governor.coalesce(&mut content, 0) > 0);
+        assert!(content.starts_with("\r\n[sshx: output throttled"));
+        assert!(content.ends_with("line\r\n"));
+
+        // The marker fits in the budget, so it is not skipped on the next pass.
+        let coalesced = content.clone();
+        assert_eq!(governor.coalesce(&mut content, 0), 0);
+        assert_eq!(content, coalesced);
+    }
+
+    #[test]
+    fn coalesce_within_budget() {
+        let governor = Governor::new(2000);
+        let mut content = "line\r\n".repeat(100);
+        assert_eq!(governor.coalesce(&mut content, 0), 0);
+        assert_eq!(content, "line\r\n".repeat(100));
+    }
+}
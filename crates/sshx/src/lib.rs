@@ -6,7 +6,20 @@
 #![deny(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod compress;
+pub mod config;
+#[cfg(unix)]
+pub mod console;
+#[cfg(unix)]
+pub mod control;
 pub mod controller;
 pub mod encrypt;
+pub mod governor;
+pub mod record;
 pub mod runner;
+pub mod shell_info;
 pub mod terminal;
+pub mod tmux;
+pub mod transport;
+#[cfg(unix)]
+pub mod viewer;
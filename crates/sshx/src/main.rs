@@ -1,36 +1,424 @@
+use std::future::{self, Future};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 
 use ansi_term::Color::{Cyan, Fixed, Green};
-use anyhow::Result;
-use clap::Parser;
-use sshx::{controller::Controller, runner::Runner, terminal::get_default_shell};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use sshx::config::{Config, Profile};
+use sshx::controller::{Controller, Credentials, Event, JoinRequest};
+use sshx::governor::MIN_RATE;
+use sshx::runner::Runner;
+use sshx::terminal::{check_run_as, get_default_shell, Command, Limits, Sandbox};
+use sshx::tmux::TmuxSession;
+use sshx::transport::{Proxy, TlsOptions, Transport};
 use tokio::signal;
-use tracing::error;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Server used when none is given on the command line or in a profile.
+const DEFAULT_SERVER: &str = "https://sshx.io";
 
 /// A secure web-based, collaborative terminal.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Address of the remote sshx server.
-    #[clap(long, default_value = "https://sshx.io", env = "SSHX_SERVER")]
-    server: String,
+    #[clap(subcommand)]
+    subcommand: Option<Commands>,
+
+    /// Address of the remote sshx server (defaults to https://sshx.io).
+    #[clap(long, env = "SSHX_SERVER")]
+    server: Option<String>,
+
+    /// Proxy to connect to the server through, like "http://proxy:3128" or
+    /// "socks5://proxy:1080" (defaults to HTTPS_PROXY or ALL_PROXY).
+    #[clap(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// PEM file with extra root certificates to trust, for private CAs.
+    #[clap(long, value_name = "PATH")]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM file with a client certificate, for servers that require one.
+    #[clap(long, value_name = "PATH", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the client certificate.
+    #[clap(long, value_name = "PATH", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Base64 SHA-256 hash of the server's public key, may be repeated. The
+    /// connection fails unless the server uses one of these keys.
+    #[clap(long = "pin-sha256", value_name = "HASH")]
+    pin_sha256: Vec<String>,
+
+    /// Profile from the config file to take default options from.
+    #[clap(long, env = "SSHX_PROFILE")]
+    profile: Option<String>,
+
+    /// Path to the config file (defaults to ~/.config/sshx/config.toml).
+    #[clap(long, value_name = "PATH", env = "SSHX_CONFIG")]
+    config: Option<PathBuf>,
 
     /// Local shell command to run in the terminal.
     #[clap(long)]
     shell: Option<String>,
 
+    /// Working directory for the shell or command.
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+
+    /// Extra environment variable for the shell or command, may be repeated.
+    #[clap(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+
+    /// Run shells in a sandbox on Linux, where the file system is read-only
+    /// apart from an empty /tmp, and other processes are hidden.
+    #[clap(long, conflicts_with_all = ["stdin", "tmux"])]
+    sandbox: bool,
+
+    /// Also cut off network access from sandboxed shells.
+    #[clap(long, requires = "sandbox")]
+    isolate_network: bool,
+
+    /// Limit the CPU time of each process in a shell, like "10m".
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration, conflicts_with_all = ["stdin", "tmux"])]
+    limit_cpu: Option<Duration>,
+
+    /// Limit the memory of a shell, like "512M", when sshx runs in a delegated
+    /// cgroup, or else the address space of each process in it.
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, conflicts_with_all = ["stdin", "tmux"])]
+    limit_mem: Option<u64>,
+
+    /// Limit the number of processes in a shell when sshx runs in a delegated
+    /// cgroup, or else the number of processes of your user.
+    #[clap(long, value_name = "N", conflicts_with_all = ["stdin", "tmux"])]
+    limit_procs: Option<u64>,
+
+    /// Limit the size of files written from a shell, like "1G".
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, conflicts_with_all = ["stdin", "tmux"])]
+    limit_fsize: Option<u64>,
+
+    /// How long shells have to exit after they are hung up, when they are
+    /// closed or the session ends, before they are killed.
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "5s")]
+    grace_period: Duration,
+
+    /// Limit the output sent from each shell to this many bytes per second,
+    /// like "256K". Output beyond that is skipped, keeping the latest screen.
+    /// The rate must be at least 1K.
+    #[clap(long, value_name = "SIZE", value_parser = parse_output_rate)]
+    output_rate: Option<u64>,
+
+    /// Compress terminal output before it is encrypted, to save bandwidth.
+    /// This is off by default, since the size of compressed messages can reveal
+    /// something about the output to anyone watching the connection.
+    #[clap(long)]
+    compress: bool,
+
+    /// Run shells as this unprivileged user, which needs sshx to run as root.
+    /// Shells start in the user's home directory with its login shell, unless
+    /// other options are given, and do not inherit the environment of sshx.
+    #[clap(long, value_name = "USER", conflicts_with_all = ["stdin", "tmux"])]
+    run_as: Option<String>,
+
     /// Quiet mode, only prints the URL to stdout.
-    #[clap(short, long)]
+    #[clap(short, long, overrides_with = "no_quiet")]
     quiet: bool,
 
+    /// Turn off quiet mode, even if the profile turns it on.
+    #[clap(long, overrides_with = "quiet")]
+    no_quiet: bool,
+
+    /// Format of the session details printed to stdout.
+    ///
+    /// With "json", one object describing the session is printed, followed by
+    /// one line for each later connection event.
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with_all = ["quiet", "mirror"])]
+    output: OutputFormat,
+
     /// Session name displayed in the title (defaults to user@hostname).
     #[clap(long)]
     name: Option<String>,
 
     /// Enable read-only access mode - generates separate URLs for viewers and
     /// editors.
-    #[clap(long)]
+    #[clap(long, overrides_with = "no_enable_readers")]
     enable_readers: bool,
+
+    /// Turn off read-only access mode, even if the profile turns it on.
+    #[clap(long, overrides_with = "enable_readers")]
+    no_enable_readers: bool,
+
+    /// Ask in this terminal before letting each new user into the session.
+    #[clap(long, conflicts_with = "mirror")]
+    approve_joins: bool,
+
+    /// Mirror the first shell in this terminal, so you can type alongside
+    /// everyone else. The session ends when that shell exits.
+    #[clap(long, conflicts_with = "quiet")]
+    mirror: bool,
+
+    /// Share what is piped to standard input as a single read-only terminal,
+    /// like `tail -f app.log | sshx --stdin`.
+    #[clap(long, conflicts_with_all = [
+        "shell", "command", "mirror", "approve_joins", "enable_readers", "session_file",
+    ])]
+    stdin: bool,
+
+    /// Show each window of an existing tmux session as a shell, instead of
+    /// starting new shells. Windows opened or closed on either side are kept
+    /// in sync.
+    #[clap(long, value_name = "SESSION", conflicts_with_all = [
+        "shell", "command", "stdin", "cwd", "env", "session_file",
+    ])]
+    tmux: Option<String>,
+
+    /// Open this many shells as soon as the session starts, rather than when
+    /// someone first joins, so that no output is missed.
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..=14),
+        conflicts_with_all = ["stdin", "tmux"],
+    )]
+    initial_shells: Option<u32>,
+
+    /// How the initial shells are arranged in the web interface.
+    #[clap(long, value_enum, default_value_t = Layout::Grid, requires = "initial_shells")]
+    layout: Layout,
+
+    /// Close the session after this much time, like "2h" or "90m".
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Close the session after no input or output for this long, like "15m".
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// Record every shell to an asciicast file in this directory, with its
+    /// output, input and resizes.
+    #[clap(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+
+    /// Save the session to this file, and resume it from there on restart.
+    ///
+    /// The session is left open on exit so that it can be resumed. Delete the
+    /// file to start a new session instead.
+    #[clap(long, value_name = "PATH")]
+    session_file: Option<PathBuf>,
+
+    /// Command to run in each terminal instead of a shell, with its arguments.
+    #[clap(last = true, value_name = "COMMAND", conflicts_with = "shell")]
+    command: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Join a session from this terminal, without a browser.
+    ///
+    /// Displays one shell of the session here. Keystrokes are sent to the
+    /// shell if the link is writable. Press Ctrl-] to detach.
+    Attach {
+        /// Link to the session, as printed by `sshx`.
+        url: String,
+
+        /// ID of the shell to display (defaults to the first one).
+        #[clap(long)]
+        id: Option<u32>,
+    },
+
+    /// Manage a session that is running on this machine.
+    Ctl {
+        /// Name of the session, needed if several are running.
+        #[clap(long)]
+        session: Option<String>,
+
+        /// Path to the control socket of the session.
+        #[clap(long, value_name = "PATH", conflicts_with = "session")]
+        socket: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// List the users connected to the session.
+    Users,
+    /// Print the links to the session.
+    Urls,
+    /// Disconnect a user from the session. They can rejoin with the link.
+    Kick {
+        /// ID of the user, as listed by `sshx ctl users`.
+        user_id: u32,
+    },
+    /// Open a new shell.
+    Spawn,
+    /// Close a shell.
+    Close {
+        /// ID of the shell.
+        id: u32,
+    },
+    /// Stop all users from typing ("on"), or allow it again ("off").
+    ReadOnly {
+        #[clap(value_enum)]
+        state: Switch,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Switch {
+    On,
+    Off,
+}
+
+impl Args {
+    /// Fill in options that were not set by flags or the environment from the
+    /// selected profile in the config file.
+    fn load_profile(&mut self) -> Result<()> {
+        if self.subcommand.is_some() {
+            return Ok(());
+        }
+        let config = match &self.config {
+            Some(path) if !path.exists() => {
+                bail!("config file {} does not exist", path.display());
+            }
+            Some(path) => Config::load(path)?,
+            None => match Config::default_path() {
+                Some(path) => Config::load(&path)?,
+                None => Config::default(),
+            },
+        };
+        self.apply_profile(config.profile(self.profile.as_deref())?);
+        Ok(())
+    }
+
+    fn apply_profile(&mut self, profile: Profile) {
+        self.server = self.server.take().or(profile.server);
+        self.proxy = self.proxy.take().or(profile.proxy);
+        self.ca_cert = self.ca_cert.take().or(profile.ca_cert);
+        if self.client_cert.is_none() && self.client_key.is_none() {
+            self.client_cert = profile.client_cert;
+            self.client_key = profile.client_key;
+        }
+        if self.pin_sha256.is_empty() {
+            self.pin_sha256 = profile.pin_sha256.unwrap_or_default();
+        }
+        self.shell = self.shell.take().or(profile.shell);
+        self.name = self.name.take().or(profile.name);
+        // Flags given on the command line win over the profile, either way.
+        if !self.enable_readers && !self.no_enable_readers {
+            self.enable_readers = profile.enable_readers.unwrap_or(false);
+        }
+        // The --mirror flag conflicts with quiet mode, so it takes precedence.
+        if !self.quiet && !self.no_quiet {
+            self.quiet = profile.quiet.unwrap_or(false) && !self.mirror;
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    /// Rows and columns of about the same length.
+    Grid,
+    /// A single row, from left to right.
+    Row,
+    /// A single column, from top to bottom.
+    Column,
+}
+
+impl Layout {
+    /// Positions of `count` shells, which are about 750 by 515 pixels in the
+    /// web interface, with some space between them.
+    fn positions(self, count: u32) -> Vec<(i32, i32)> {
+        const SPACING: (u32, u32) = (780, 545);
+        let columns = match self {
+            Layout::Grid => (count as f64).sqrt().ceil() as u32,
+            Layout::Row => count,
+            Layout::Column => 1,
+        };
+        (0..count)
+            .map(|i| {
+                let (col, row) = (i % columns, i / columns);
+                ((col * SPACING.0) as i32, (row * SPACING.1) as i32)
+            })
+            .collect()
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human-readable text.
+    Text,
+    /// JSON lines, for use in scripts.
+    Json,
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String)> {
+    let (key, value) = s.split_once('=').context("expected KEY=VALUE")?;
+    if key.is_empty() {
+        bail!("environment variable name is empty");
+    }
+    Ok((key.into(), value.into()))
+}
+
+/// Parse a number of bytes with an optional binary suffix, like "512M".
+fn parse_size(s: &str) -> Result<u64> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let value: u64 = digits.parse().context("expected a size like 512M or 1G")?;
+    value
+        .checked_shl(shift)
+        .filter(|v| v >> shift == value)
+        .context("size is too large")
+}
+
+/// Parse an output rate, which must be at least the governor's minimum.
+fn parse_output_rate(s: &str) -> Result<u64> {
+    let rate = parse_size(s)?;
+    if rate < MIN_RATE {
+        bail!("output rate must be at least {MIN_RATE} bytes per second");
+    }
+    Ok(rate)
+}
+
+/// Print the session details as a single line of JSON.
+fn print_json(server: &str, controller: &Controller, control_socket: Option<&Path>) {
+    let details = serde_json::json!({
+        "name": controller.name(),
+        "url": controller.url(),
+        "write_url": controller.write_url(),
+        "server": server,
+        "control_socket": control_socket,
+        "version": option_env!("CARGO_PKG_VERSION"),
+    });
+    println!("{details}");
+}
+
+/// Print a connection event as a single line of JSON.
+fn print_json_event(event: &Event) {
+    println!("{}", serde_json::to_string(event).unwrap());
+}
+
+/// Print connection events as JSON lines until the controller is dropped.
+async fn print_json_events(mut events: broadcast::Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => print_json_event(&event),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
 }
 
 fn print_greeting(shell: &str, controller: &Controller) {
@@ -73,10 +461,48 @@ fn print_greeting(shell: &str, controller: &Controller) {
 
 #[tokio::main]
 async fn start(args: Args) -> Result<()> {
-    let shell = match args.shell {
-        Some(shell) => shell,
-        None => get_default_shell().await,
+    // Refuse to start if privileges cannot be dropped to the user.
+    let login_shell = match &args.run_as {
+        Some(user) => Some(check_run_as(user)?),
+        None => None,
+    };
+    let mut command = match args.command.split_first() {
+        Some((program, rest)) => {
+            let mut command = Command::new(program.as_str());
+            command.args = rest.to_vec();
+            command
+        }
+        None => match args.shell.or(login_shell) {
+            Some(shell) => Command::new(shell),
+            None => Command::new(get_default_shell().await),
+        },
+    };
+    if let Some(cwd) = &args.cwd {
+        if !cwd.is_dir() {
+            bail!("working directory {} does not exist", cwd.display());
+        }
+    }
+    command.cwd = args.cwd;
+    command.env = args.env;
+    command.run_as = args.run_as;
+    command.limits = Limits {
+        cpu_secs: args.limit_cpu.map(|cpu| cpu.as_secs().max(1)),
+        memory: args.limit_mem,
+        processes: args.limit_procs,
+        file_size: args.limit_fsize,
     };
+    if args.sandbox {
+        command.sandbox = Some(Sandbox {
+            isolate_network: args.isolate_network,
+        });
+    }
+
+    if args.mirror && !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        bail!("--mirror requires an interactive terminal");
+    }
+    if args.stdin && std::io::stdin().is_terminal() {
+        bail!("--stdin requires input to be piped, like `tail -f app.log | sshx --stdin`");
+    }
 
     let name = args.name.unwrap_or_else(|| {
         let mut name = whoami::username();
@@ -89,9 +515,71 @@ async fn start(args: Args) -> Result<()> {
         name
     });
 
-    let runner = Runner::Shell(shell.clone());
-    let mut controller = Controller::new(&args.server, &name, runner, args.enable_readers).await?;
-    if args.quiet {
+    let server = args.server.as_deref().unwrap_or(DEFAULT_SERVER);
+    let mut transport = Transport::new(server)?;
+    if let Some(proxy) = &args.proxy {
+        transport = transport.with_proxy(Some(Proxy::parse(proxy)?));
+    }
+    if args.client_cert.is_some() != args.client_key.is_some() {
+        bail!("a client certificate and its key must be given together");
+    }
+    let tls = TlsOptions {
+        ca_cert: args.ca_cert,
+        client_identity: args.client_cert.zip(args.client_key),
+        pin_sha256: args.pin_sha256,
+    };
+    let transport = transport.with_tls(&tls)?;
+    let (shell, runner) = if args.stdin {
+        (String::from("standard input (read-only)"), Runner::Pipe)
+    } else if let Some(name) = &args.tmux {
+        let session = TmuxSession::attach(name).await?;
+        (format!("tmux session {name}"), Runner::Tmux { session })
+    } else {
+        let mut shell = command.to_string();
+        if let Some(user) = &command.run_as {
+            shell += &format!(" as {user}");
+        }
+        if command.sandbox.is_some() {
+            shell += " (sandboxed)";
+        }
+        (shell, Runner::Command(command))
+    };
+    let resumed = match &args.session_file {
+        Some(path) => resume_session(path, &transport, runner.clone()).await?,
+        None => None,
+    };
+    let is_resumed = resumed.is_some();
+    let mut controller = match resumed {
+        Some(controller) => controller,
+        None => {
+            Controller::builder(transport, runner)
+                .name(&name)
+                .enable_readers(args.enable_readers)
+                .require_approval(args.approve_joins)
+                .compress(args.compress)
+                .build()
+                .await?
+        }
+    };
+    if let Some(path) = &args.session_file {
+        controller
+            .credentials()
+            .save(path)
+            .with_context(|| format!("failed to write session file {}", path.display()))?;
+    }
+
+    // Lets `sshx ctl` manage the session while it runs.
+    #[cfg(unix)]
+    let control = open_control_socket(&controller);
+    #[cfg(unix)]
+    let control_path = control.as_ref().map(|socket| socket.path());
+    #[cfg(not(unix))]
+    let control_path = None;
+
+    if args.output == OutputFormat::Json {
+        print_json(server, &controller, control_path);
+        tokio::spawn(print_json_events(controller.subscribe_events()));
+    } else if args.quiet {
         if let Some(write_url) = controller.write_url() {
             println!("{}", write_url);
         } else {
@@ -101,28 +589,294 @@ async fn start(args: Args) -> Result<()> {
         print_greeting(&shell, &controller);
     }
 
+    if args.approve_joins {
+        tokio::spawn(approve_joins(controller.join_requests()));
+    }
+    controller.set_grace_period(args.grace_period);
+    if let Some(rate) = args.output_rate {
+        controller.limit_output_rate(rate);
+    }
+    if let Some(dir) = &args.record {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create recording directory {}", dir.display()))?;
+        controller.record_to(dir);
+    }
+
+    let mirror_exit = start_mirror(&mut controller, args.mirror)?;
+    if let Some(count) = args.initial_shells {
+        if is_resumed {
+            info!("not opening initial shells, since the session was resumed");
+        } else {
+            controller.open_shells(&args.layout.positions(count));
+        }
+    }
+    let max_duration = max_duration(args.max_duration);
+    let idle_timeout = idle_timeout(controller.activity(), args.idle_timeout);
+
     let exit_signal = signal::ctrl_c();
     tokio::pin!(exit_signal);
-    tokio::select! {
-        _ = controller.run() => unreachable!(),
-        Ok(()) = &mut exit_signal => (),
+    let (reason, expired) = tokio::select! {
+        result = controller.run() => {
+            result?;
+            (String::from("stopped"), false)
+        }
+        Ok(()) = &mut exit_signal => (String::from("interrupted"), false),
+        _ = mirror_exit => {
+            info!("mirrored shell exited, ending the session");
+            (String::from("mirrored shell exited"), false)
+        }
+        reason = max_duration => (reason, true),
+        reason = idle_timeout => (reason, true),
     };
-    controller.close().await?;
+    let json = args.output == OutputFormat::Json;
+    // Expired sessions are closed even if they could be resumed.
+    match &args.session_file {
+        Some(path) if !expired => {
+            info!("leaving session open, resume it from {}", path.display());
+            if json {
+                print_json_event(&Event::LeftOpen { reason });
+            }
+        }
+        _ => {
+            if expired && !json {
+                eprintln!("Session closed: {reason}");
+            }
+            controller.close().await?;
+            if json {
+                print_json_event(&Event::Closed { reason });
+            }
+        }
+    }
 
     Ok(())
 }
 
+/// Join a session as a terminal-native viewer.
+#[tokio::main]
+async fn attach(url: &str, id: Option<u32>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        if !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+            bail!("attaching requires an interactive terminal");
+        }
+        let url = sshx::viewer::SessionUrl::parse(url)?;
+        sshx::viewer::attach(&url, id.map(sshx_core::Sid)).await
+    }
+    #[cfg(not(unix))]
+    {
+        _ = (url, id);
+        bail!("attaching is not supported on this platform");
+    }
+}
+
+/// Send a command to a session running on this machine.
+#[tokio::main]
+async fn ctl(session: Option<&str>, socket: Option<&Path>, command: &CtlCommand) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use sshx::control::{self, Request, Response};
+        use sshx_core::{Sid, Uid};
+
+        let path = match socket {
+            Some(path) => path.to_owned(),
+            None => control::find_socket(session)?,
+        };
+        let request = match *command {
+            CtlCommand::Users => Request::Users,
+            CtlCommand::Urls => Request::Urls,
+            CtlCommand::Kick { user_id } => Request::Kick {
+                user_id: Uid(user_id),
+            },
+            CtlCommand::Spawn => Request::CreateShell,
+            CtlCommand::Close { id } => Request::CloseShell { id: Sid(id) },
+            CtlCommand::ReadOnly { state } => Request::SetReadOnly {
+                read_only: state == Switch::On,
+            },
+        };
+        match control::request(&path, &request).await? {
+            Response::Ok => (),
+            Response::Users { users, read_only } => {
+                if read_only {
+                    println!("The session is read-only.\n");
+                }
+                println!("{:<6} {:<24} ACCESS", "ID", "NAME");
+                for user in users {
+                    // Names are chosen by users, so don't let them control the terminal.
+                    let name: String = user.name.chars().filter(|c| !c.is_control()).collect();
+                    let access = if user.can_write { "write" } else { "read" };
+                    println!("{:<6} {name:<24} {access}", user.id.0);
+                }
+            }
+            Response::Urls { url, write_url } => match write_url {
+                Some(write_url) => {
+                    println!("Read-only link: {url}");
+                    println!("Writable link:  {write_url}");
+                }
+                None => println!("Link: {url}"),
+            },
+            Response::Error { message } => bail!(message),
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        _ = (session, socket, command);
+        bail!("controlling sessions is not supported on this platform");
+    }
+}
+
+/// Open the control socket of a session, logging a warning if that fails.
+#[cfg(unix)]
+fn open_control_socket(controller: &Controller) -> Option<sshx::control::ControlSocket> {
+    let path = sshx::control::socket_path(controller.name());
+    match sshx::control::ControlSocket::bind(&path, controller.handle()) {
+        Ok(socket) => {
+            debug!(path = %path.display(), "listening for sshx ctl commands");
+            Some(socket)
+        }
+        Err(err) => {
+            warn!(?err, "failed to open control socket");
+            None
+        }
+    }
+}
+
+/// Mirror the next shell in the local terminal, if requested.
+///
+/// Returns a future that resolves when the mirrored shell has exited.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn start_mirror(controller: &mut Controller, mirror: bool) -> Result<impl Future<Output = ()>> {
+    let done_rx = if mirror {
+        #[cfg(unix)]
+        {
+            println!("  Waiting for a shell to be opened, it will be mirrored here.\n");
+            Some(controller.mirror_next_shell())
+        }
+        #[cfg(not(unix))]
+        bail!("--mirror is not supported on this platform");
+    } else {
+        None
+    };
+    Ok(async move {
+        match done_rx {
+            Some(done_rx) => _ = done_rx.await,
+            None => future::pending().await,
+        }
+    })
+}
+
+/// Ask on standard input whether to let each user waiting to join in.
+async fn approve_joins(mut requests: mpsc::Receiver<JoinRequest>) {
+    let mut lines = read_lines();
+    while let Some(request) = requests.recv().await {
+        // The name is chosen by the user, so don't let it control the terminal.
+        let name: String = request.name.chars().filter(|c| !c.is_control()).collect();
+        eprint!("{name} wants to join the session. Let them in? [y/N] ");
+        let approved = match lines.recv().await {
+            Some(line) => matches!(line.trim(), "y" | "Y" | "yes"),
+            None => false,
+        };
+        info!(user_id = %request.user_id, %name, approved, "answered join request");
+        if request.answer(approved).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Read lines from standard input on a separate thread.
+///
+/// This avoids `tokio::io::stdin()`, which blocks the runtime from shutting
+/// down while a read is pending.
+fn read_lines() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(1);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Resolves with a reason once the session has lasted for `limit`.
+async fn max_duration(limit: Option<Duration>) -> String {
+    match limit {
+        Some(limit) => {
+            time::sleep(limit).await;
+            format!(
+                "reached the maximum duration of {}",
+                humantime::format_duration(limit)
+            )
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Resolves with a reason once there has been no activity for `timeout`.
+async fn idle_timeout(activity: watch::Receiver<Instant>, timeout: Option<Duration>) -> String {
+    let Some(timeout) = timeout else {
+        return future::pending().await;
+    };
+    loop {
+        let deadline = *activity.borrow() + timeout;
+        if Instant::now() >= deadline {
+            return format!("idle for {}", humantime::format_duration(timeout));
+        }
+        time::sleep_until(deadline).await;
+    }
+}
+
+/// Reattach to the session saved in a file, if it is still open on the server.
+async fn resume_session(
+    path: &Path,
+    transport: &Transport,
+    runner: Runner,
+) -> Result<Option<Controller>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let credentials = Credentials::load(path)
+        .with_context(|| format!("failed to read session file {}", path.display()))?;
+    if credentials.origin != transport.origin() {
+        warn!(origin = %credentials.origin, "saved session is for another server, ignoring it");
+        return Ok(None);
+    }
+    let controller =
+        Controller::resume_with_transport(transport.clone(), credentials, runner).await?;
+    if controller.is_none() {
+        warn!("saved session has ended, starting a new one");
+    }
+    Ok(controller)
+}
+
 fn main() -> ExitCode {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    let profile_result = args.load_profile();
 
-    let default_level = if args.quiet { "error" } else { "info" };
+    // Log lines would be garbled by a terminal in raw mode.
+    let default_level = if args.quiet || args.mirror || args.subcommand.is_some() {
+        "error"
+    } else {
+        "info"
+    };
 
     tracing_subscriber::fmt()
         .with_env_filter(std::env::var("RUST_LOG").unwrap_or(default_level.into()))
         .with_writer(std::io::stderr)
         .init();
 
-    match start(args) {
+    let result = profile_result.and_then(|()| match &args.subcommand {
+        Some(Commands::Attach { url, id }) => attach(url, *id),
+        Some(Commands::Ctl {
+            session,
+            socket,
+            command,
+        }) => ctl(session.as_deref(), socket.as_deref(), command),
+        None => start(args),
+    });
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             error!("{err:?}");
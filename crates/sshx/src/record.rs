@@ -0,0 +1,174 @@
+//! Recording of shells to files in the asciicast v2 format.
+//!
+//! See <https://docs.asciinema.org/manual/asciicast/v2/> for the format, which
+//! can be played back with `asciinema play`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use sshx_core::Sid;
+use tracing::warn;
+
+/// Flush buffered events to the file at least this often while they arrive.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes the output, input and resizes of one shell to an asciicast file.
+///
+/// Events are buffered, and flushed to the file periodically, when
+/// [`Recorder::flush`] is called, and when the recorder is dropped.
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    start: Instant,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    /// Create a new recording for a shell in the directory `dir`.
+    ///
+    /// The file is named after the session and shell ID, with a numeric suffix
+    /// if that file already exists, so earlier recordings are never replaced.
+    pub fn create(dir: &Path, session: &str, id: Sid) -> io::Result<Self> {
+        let mut suffix = 0;
+        let (path, mut file) = loop {
+            let file_name = match suffix {
+                0 => format!("{session}-{id}.cast"),
+                n => format!("{session}-{id}.{n}.cast"),
+            };
+            let path = dir.join(file_name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(err) => return Err(err),
+            }
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        // Shells are spawned with 24 rows and 80 columns.
+        let header = json!({
+            "version": 2,
+            "width": 80,
+            "height": 24,
+            "timestamp": timestamp,
+            "title": format!("sshx {session}, shell {id}"),
+            "env": { "TERM": "xterm-256color" },
+        });
+        writeln!(file, "{header}")?;
+
+        Ok(Self {
+            path,
+            file: Some(BufWriter::new(file)),
+            start: Instant::now(),
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Returns the path of the file being written.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record output from the shell.
+    pub fn output(&mut self, data: &str) {
+        if !data.is_empty() {
+            self.event("o", data);
+        }
+    }
+
+    /// Record input sent to the shell by a user.
+    pub fn input(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.event("i", &String::from_utf8_lossy(data));
+        }
+    }
+
+    /// Record a marker with a label, such as who is typing.
+    pub fn marker(&mut self, label: &str) {
+        self.event("m", label);
+    }
+
+    /// Record a change in the size of the shell.
+    pub fn resize(&mut self, rows: u32, cols: u32) {
+        self.event("r", &format!("{cols}x{rows}"));
+    }
+
+    /// Write buffered events to the file.
+    pub fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.flush() {
+                self.fail(err);
+            }
+        }
+    }
+
+    fn event(&mut self, code: &str, data: &str) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let time = self.start.elapsed().as_secs_f64();
+        let line = format!("{}\n", json!([time, code, data]));
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            self.fail(err);
+        } else if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Stop recording rather than interrupting the shell.
+    fn fail(&mut self, err: io::Error) {
+        warn!(path = %self.path.display(), ?err, "failed to write recording");
+        self.file = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::Value;
+    use sshx_core::Sid;
+
+    use super::Recorder;
+
+    #[test]
+    fn record_events() {
+        let dir = std::env::temp_dir().join(format!("sshx-record-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut recorder = Recorder::create(&dir, "abc", Sid(1)).unwrap();
+        recorder.output("hello\r\n");
+        recorder.input(b"ls\r");
+        recorder.resize(40, 120);
+        recorder.output("");
+        let path = recorder.path().to_owned();
+        drop(recorder);
+        assert_eq!(path, dir.join("abc-1.cast"));
+
+        let second = Recorder::create(&dir, "abc", Sid(1)).unwrap();
+        assert_eq!(second.path(), dir.join("abc-1.1.cast"));
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "hello\r\n");
+        assert_eq!(lines[2][1], "i");
+        assert_eq!(lines[2][2], "ls\r");
+        assert_eq!(lines[3][1], "r");
+        assert_eq!(lines[3][2], "120x40");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
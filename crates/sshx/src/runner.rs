@@ -1,53 +1,223 @@
 //! Defines tasks that control the behavior of a single shell in the client.
 
+use std::io;
+
 use anyhow::Result;
 use encoding_rs::{CoderResult, UTF_8};
-use sshx_core::proto::{client_update::ClientMessage, TerminalData};
-use sshx_core::Sid;
+use sshx_core::proto::{client_update::ClientMessage, Compression, TerminalData};
+use sshx_core::{Sid, Uid};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     sync::mpsc,
+    time::{self, Duration, Instant, MissedTickBehavior},
 };
+use tracing::{debug, info, warn};
 
+use crate::compress::deflate;
 use crate::encrypt::Encrypt;
-use crate::terminal::Terminal;
+use crate::governor::{Governor, THROTTLE_INTERVAL};
+use crate::record::Recorder;
+use crate::shell_info::{OscParser, ShellInfo};
+use crate::terminal::{Command, ExitStatus, Terminal, Termination};
+use crate::tmux::{TmuxSession, TmuxWindow};
 
 const CONTENT_CHUNK_SIZE: usize = 1 << 16; // Send at most this many bytes at a time.
 const CONTENT_ROLLING_BYTES: usize = 8 << 20; // Store at least this much content.
 const CONTENT_PRUNE_BYTES: usize = 12 << 20; // Prune when we exceed this length.
 
+// The server only stores 2 MiB per shell, so throttled shells keep less
+// content.
+const THROTTLED_ROLLING_BYTES: usize = 2 << 20;
+const THROTTLED_PRUNE_BYTES: usize = 3 << 20;
+
+/// Interval for checking the foreground process and resource limits of a
+/// terminal, and for flushing its recording.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Variants of terminal behavior that are used by the controller.
 #[derive(Debug, Clone)]
 pub enum Runner {
     /// Spawns the specified shell as a subprocess, forwarding PTYs.
     Shell(String),
 
+    /// Spawns a command with arguments and environment, forwarding PTYs.
+    Command(Command),
+
+    /// Publishes standard input as the output of a single read-only shell.
+    Pipe,
+
+    /// Shows each window of an existing tmux session as a shell.
+    Tmux {
+        /// Session whose windows are shown.
+        session: TmuxSession,
+    },
+
     /// Mock runner that only echos its input, useful for testing.
     Echo,
 }
 
+/// Collaborator who typed some input, as reported by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Author {
+    /// ID of the user in the session.
+    pub id: Uid,
+    /// Display name of the user when they typed the input.
+    pub name: String,
+}
+
+impl Author {
+    /// Create an author, naming them after their ID if the name is empty.
+    pub fn new(id: Uid, name: String) -> Self {
+        let name = if name.is_empty() {
+            format!("User {id}")
+        } else {
+            name
+        };
+        Self { id, name }
+    }
+}
+
 /// Internal message routed to shell runners.
 pub enum ShellData {
-    /// Sequence of input bytes from the server.
-    Data(Vec<u8>),
+    /// Sequence of input bytes, with the remote user who typed them. Input
+    /// from the host's own terminal has no author.
+    Data(Vec<u8>, Option<Author>),
     /// Information about the server's current sequence number.
     Sync(u64),
     /// Resize the shell to a different number of rows and columns.
     Size(u32, u32),
+    /// Copy all output of the shell to a local listener, starting from the
+    /// content that it has already buffered.
+    Mirror(mpsc::Sender<String>),
+    /// Record the shell to a file, starting from the content that it has
+    /// already buffered.
+    Record(Recorder),
+    /// Limit the output sent to the server to this many bytes per second.
+    RateLimit(u64),
+    /// A user closed the shell, or the session is closing. Its processes are
+    /// hung up and given this grace period to exit before they are killed.
+    /// Dropping the channel also ends the shell, but kills its processes right
+    /// away and leaves tmux windows open, since they outlive the session.
+    Close(Duration),
 }
 
 impl Runner {
+    /// Returns whether this runner can only run one shell, which is opened by
+    /// the client rather than by users.
+    pub fn is_single_shell(&self) -> bool {
+        matches!(self, Self::Pipe)
+    }
+
     /// Asynchronous task to run a single shell with process I/O.
+    ///
+    /// The output stream starts at sequence number `seq`, which is nonzero
+    /// when restoring a shell that the server already has data for. Output is
+    /// compressed before it is encrypted if `compress` is set. Returns how the
+    /// process ended, if it exited on its own.
     pub async fn run(
         &self,
         id: Sid,
         encrypt: Encrypt,
+        compress: bool,
+        seq: u64,
         shell_rx: mpsc::Receiver<ShellData>,
         output_tx: mpsc::Sender<ClientMessage>,
-    ) -> Result<()> {
+    ) -> Result<Option<ExitStatus>> {
+        let process = match self {
+            Self::Shell(shell) => Process::spawn(&Command::new(shell.as_str())).await?,
+            Self::Command(command) => Process::spawn(command).await?,
+            #[cfg(unix)]
+            Self::Pipe => Process::Stdin(crate::console::read_stdin()),
+            #[cfg(not(unix))]
+            Self::Pipe => anyhow::bail!("reading standard input is not supported on this platform"),
+            Self::Tmux { session } => Process::Tmux(Box::new(session.open().await?)),
+            Self::Echo => {
+                echo_task(id, encrypt, seq, shell_rx, output_tx).await?;
+                return Ok(None);
+            }
+        };
+        shell_task(id, encrypt, compress, process, seq, shell_rx, output_tx).await
+    }
+}
+
+/// Source of output for a shell, which also receives its input.
+enum Process {
+    /// Pseudoterminal of a spawned command.
+    Terminal(Box<Terminal>),
+    /// Chunks read from standard input, which does not accept input.
+    Stdin(mpsc::Receiver<Vec<u8>>),
+    /// Window of a tmux session.
+    Tmux(Box<TmuxWindow>),
+}
+
+impl Process {
+    async fn spawn(command: &Command) -> Result<Self> {
+        let mut term = Terminal::spawn(command).await?;
+        term.set_winsize(24, 80)?;
+        Ok(Self::Terminal(Box::new(term)))
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Terminal(term) => term.read(buf).await,
+            Self::Stdin(stdin_rx) => match stdin_rx.recv().await {
+                Some(chunk) => {
+                    let n = chunk.len().min(buf.len());
+                    buf[..n].copy_from_slice(&chunk[..n]);
+                    Ok(n)
+                }
+                None => Ok(0),
+            },
+            Self::Tmux(window) => Ok(window.read(buf).await),
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Terminal(term) => Ok(term.write_all(data).await?),
+            Self::Stdin(_) => Ok(()), // read-only, so input is dropped
+            Self::Tmux(window) => window.write(data).await,
+        }
+    }
+
+    async fn set_winsize(&mut self, rows: u16, cols: u16) -> Result<()> {
+        match self {
+            Self::Terminal(term) => term.set_winsize(rows, cols),
+            Self::Stdin(_) => Ok(()),
+            Self::Tmux(window) => window.resize(rows, cols).await,
+        }
+    }
+
+    /// Get the name of the foreground process, if known.
+    fn foreground(&self) -> Option<String> {
+        match self {
+            Self::Terminal(term) => term.foreground_process(),
+            Self::Stdin(_) | Self::Tmux(_) => None,
+        }
+    }
+
+    /// Check for resource limits that were reached since the last call.
+    fn limit_events(&mut self) -> Vec<&'static str> {
+        match self {
+            Self::Terminal(term) => term.limit_events(),
+            Self::Stdin(_) | Self::Tmux(_) => Vec::new(),
+        }
+    }
+
+    /// Wait for the process to exit, after its output has ended.
+    async fn wait(&mut self) -> Result<Option<ExitStatus>> {
+        match self {
+            Self::Terminal(term) => term.wait().await,
+            Self::Stdin(_) | Self::Tmux(_) => Ok(None),
+        }
+    }
+
+    /// End the process after a user closed its shell, returning how it ended.
+    async fn close(&mut self, grace: Duration) -> Result<Option<Termination>> {
         match self {
-            Self::Shell(shell) => shell_task(id, encrypt, shell, shell_rx, output_tx).await,
-            Self::Echo => echo_task(id, encrypt, shell_rx, output_tx).await,
+            Self::Terminal(term) => Ok(Some(term.terminate(grace).await?)),
+            Self::Stdin(_) => Ok(None),
+            Self::Tmux(window) => window.kill().await.map(|()| None),
         }
     }
 }
@@ -56,48 +226,135 @@ impl Runner {
 async fn shell_task(
     id: Sid,
     encrypt: Encrypt,
-    shell: &str,
+    compress: bool,
+    mut process: Process,
+    start_seq: u64,
     mut shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
-) -> Result<()> {
-    let mut term = Terminal::new(shell).await?;
-    term.set_winsize(24, 80)?;
+) -> Result<Option<ExitStatus>> {
+    // Piped text has bare line feeds, which terminals do not return from.
+    let is_pipe = matches!(process, Process::Stdin(_));
+    let mut input_done = false; // set when a pipe has reached its end
+    let is_terminal = matches!(process, Process::Terminal(_));
 
     let mut content = String::new(); // content from the terminal
-    let mut content_offset = 0; // bytes before the first character of `content`
+    let mut content_offset = start_seq as usize; // bytes before the first character of `content`
     let mut decoder = UTF_8.new_decoder(); // UTF-8 streaming decoder
-    let mut seq = 0; // our log of the server's sequence number
+    let mut seq = start_seq as usize; // our log of the server's sequence number
+    let mut sent = start_seq as usize; // end of all output that was ever sent
     let mut seq_outdated = 0; // number of times seq has been outdated
     let mut buf = [0u8; 4096]; // buffer for reading
     let mut finished = false; // set when this is done
+    let mut exit_status = None; // how the process ended, after it closed its output
+    let mut mirror_tx: Option<mpsc::Sender<String>> = None; // local copy of the output
+    let mut recorder: Option<Recorder> = None; // recording of the shell on disk
+    let mut last_author: Option<Author> = None; // user who typed the last input
+    let mut governor: Option<Governor> = None; // budget for output sent to the server
+    let mut resume_at: Option<Instant> = None; // when to send output that was held back
+    let mut osc_parser = OscParser::new(); // reads titles and cwd from the output
+    let mut info = ShellInfo::default(); // latest details about the shell
+    let mut info_sent = ShellInfo::default(); // details last sent to the server
+    let mut info_offset: u64 = rand::random(); // offset in the encrypted info stream
+    let mut compressed_offset: u64 = rand::random(); // offset in the compressed output stream
+    let mut status_interval = time::interval(STATUS_INTERVAL);
+    status_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     while !finished {
+        let mut notices = Vec::new(); // messages about the shell for viewers
         tokio::select! {
-            result = term.read(&mut buf) => {
+            result = process.read(&mut buf), if !input_done => {
                 let n = result?;
-                if n == 0 {
+                if n == 0 && is_pipe {
+                    // Keep the shell open, so that its output can still be read.
+                    debug!(%id, "reached the end of standard input");
+                    input_done = true;
+                } else if n == 0 {
                     finished = true;
+                    exit_status = process.wait().await?;
+                    notices.extend(exit_status.and_then(|status| status.exceeded_limit()));
                 } else {
+                    let prev_len = content.len();
                     content.reserve(decoder.max_utf8_buffer_length(n).unwrap());
                     let (result, _, _) = decoder.decode_to_string(&buf[..n], &mut content, false);
                     debug_assert!(result == CoderResult::InputEmpty);
+                    if is_pipe {
+                        translate_newlines(&mut content, prev_len);
+                    }
+                    if let Some(tx) = &mirror_tx {
+                        if tx.send(content[prev_len..].to_string()).await.is_err() {
+                            mirror_tx = None;
+                        }
+                    }
+                    if let Some(recorder) = &mut recorder {
+                        recorder.output(&content[prev_len..]);
+                    }
+                    osc_parser.feed(&content[prev_len..], &mut info);
+                }
+            }
+            _ = time::sleep_until(resume_at.unwrap_or_else(Instant::now)), if resume_at.is_some() => {
+                resume_at = None;
+            }
+            _ = status_interval.tick(), if is_terminal || recorder.is_some() => {
+                info.process = process.foreground();
+                notices.extend(process.limit_events());
+                if let Some(recorder) = &mut recorder {
+                    recorder.flush();
                 }
             }
             item = shell_rx.recv() => {
                 match item {
-                    Some(ShellData::Data(data)) => {
-                        term.write_all(&data).await?;
+                    Some(ShellData::Data(data, author)) => {
+                        // Announce who is typing whenever it changes.
+                        let changed = author.as_ref().filter(|&a| last_author.as_ref() != Some(a));
+                        if let Some(Author { name, .. }) = changed {
+                            let message = format!("{name} typed into shell {id}");
+                            info!(%id, user = %name, "{message}");
+                            if let Some(recorder) = &mut recorder {
+                                recorder.marker(&message);
+                            }
+                        }
+                        last_author = author;
+                        if let Some(recorder) = &mut recorder {
+                            recorder.input(&data);
+                        }
+                        process.write_all(&data).await?;
                     }
                     Some(ShellData::Sync(seq2)) => {
                         if seq2 < seq as u64 {
                             seq_outdated += 1;
                             if seq_outdated >= 3 {
-                                seq = seq2 as usize;
+                                seq = (seq2 as usize).max(content_offset);
                             }
                         }
                     }
                     Some(ShellData::Size(rows, cols)) => {
-                        term.set_winsize(rows as u16, cols as u16)?;
+                        if let Some(recorder) = &mut recorder {
+                            recorder.resize(rows, cols);
+                        }
+                        process.set_winsize(rows as u16, cols as u16).await?;
+                    }
+                    Some(ShellData::Mirror(tx)) => {
+                        if tx.send(content.clone()).await.is_ok() {
+                            mirror_tx = Some(tx);
+                        }
+                    }
+                    Some(ShellData::Record(mut rec)) => {
+                        rec.output(&content);
+                        recorder = Some(rec);
+                    }
+                    Some(ShellData::RateLimit(rate)) => governor = Some(Governor::new(rate)),
+                    Some(ShellData::Close(grace)) => {
+                        if let Some(recorder) = &mut recorder {
+                            recorder.flush();
+                        }
+                        match process.close(grace).await {
+                            Ok(Some(termination)) => {
+                                info!(%id, "process in shell {id} {termination}");
+                            }
+                            Ok(None) => (),
+                            Err(err) => warn!(%id, ?err, "failed to close shell"),
+                        }
+                        finished = true;
                     }
                     None => finished = true, // Server closed this shell.
                 }
@@ -110,33 +367,119 @@ async fn shell_task(
             debug_assert!(result == CoderResult::InputEmpty);
         }
 
-        // Send data if the server has fallen behind.
-        if content_offset + content.len() > seq {
-            let start = prev_char_boundary(&content, seq - content_offset);
-            let end = prev_char_boundary(&content, (start + CONTENT_CHUNK_SIZE).min(content.len()));
-            let data = encrypt.segment(
-                0x100000000 | id.0 as u64, // stream number
-                (content_offset + start) as u64,
-                &content.as_bytes()[start..end],
-            );
-            let data = TerminalData {
+        // Show notices in the terminal, so that everyone watching sees them.
+        for notice in notices {
+            warn!(%id, "shell {id}: {notice}");
+            content.push_str(&format!("\r\n[sshx: {notice}]\r\n"));
+        }
+
+        // Send details about the shell when they change.
+        if info != info_sent && !finished {
+            let json = serde_json::to_vec(&info)?;
+            let data = encrypt.segment(0x300000000 | id.0 as u64, info_offset, &json);
+            let info_data = sshx_core::proto::ShellInfo {
                 id: id.0,
                 data: data.into(),
-                seq: (content_offset + start) as u64,
+                offset: info_offset,
             };
-            output_tx.send(ClientMessage::Data(data)).await?;
-            seq = content_offset + end;
-            seq_outdated = 0;
+            output_tx.send(ClientMessage::ShellInfo(info_data)).await?;
+            info_offset = info_offset.wrapping_add(json.len() as u64);
+            info_sent = info.clone();
         }
 
-        if content.len() > CONTENT_PRUNE_BYTES && seq - CONTENT_ROLLING_BYTES > content_offset {
-            let pruned = (seq - CONTENT_ROLLING_BYTES) - content_offset;
+        // Send data if the server has fallen behind.
+        if content_offset + content.len() > seq {
+            let start = prev_char_boundary(&content, seq - content_offset);
+            let mut limit = CONTENT_CHUNK_SIZE;
+            if let Some(governor) = &mut governor {
+                // Output that was sent before is encrypted at fixed offsets, so it
+                // must be sent again unchanged if the server has rewound.
+                let skipped = governor.coalesce(&mut content, sent - content_offset);
+                if skipped > 0 {
+                    debug!(%id, skipped, "throttled output of shell");
+                }
+                limit = limit.min(governor.available(Instant::now()));
+            }
+            let end = prev_char_boundary(&content, (start + limit).min(content.len()));
+            if end > start {
+                let segment = &content.as_bytes()[start..end];
+                let compressed = compress.then(|| deflate(segment)).flatten();
+                let data = match compressed {
+                    Some(compressed) => {
+                        let data = encrypt.segment(
+                            0x400000000 | id.0 as u64, // stream number
+                            compressed_offset,
+                            &compressed,
+                        );
+                        let data = TerminalData {
+                            id: id.0,
+                            data: data.into(),
+                            seq: (content_offset + start) as u64,
+                            compression: Compression::Deflate.into(),
+                            size: segment.len() as u64,
+                            offset: compressed_offset,
+                        };
+                        compressed_offset = compressed_offset.wrapping_add(compressed.len() as u64);
+                        data
+                    }
+                    None => {
+                        let data = encrypt.segment(
+                            0x100000000 | id.0 as u64, // stream number
+                            (content_offset + start) as u64,
+                            segment,
+                        );
+                        TerminalData {
+                            id: id.0,
+                            data: data.into(),
+                            seq: (content_offset + start) as u64,
+                            ..Default::default()
+                        }
+                    }
+                };
+                output_tx.send(ClientMessage::Data(data)).await?;
+                seq = content_offset + end;
+                sent = sent.max(seq);
+                seq_outdated = 0;
+                if let Some(governor) = &mut governor {
+                    governor.consume(end - start);
+                }
+            }
+            // Come back for output that was held back, even if the shell is quiet.
+            if governor.is_some() && content_offset + content.len() > seq {
+                resume_at.get_or_insert_with(|| Instant::now() + THROTTLE_INTERVAL);
+            }
+        }
+
+        let (rolling_bytes, prune_bytes) = match governor {
+            Some(_) => (THROTTLED_ROLLING_BYTES, THROTTLED_PRUNE_BYTES),
+            None => (CONTENT_ROLLING_BYTES, CONTENT_PRUNE_BYTES),
+        };
+        if content.len() > prune_bytes && seq.saturating_sub(rolling_bytes) > content_offset {
+            let pruned = (seq - rolling_bytes) - content_offset;
             let pruned = prev_char_boundary(&content, pruned);
             content_offset += pruned;
             content.drain(..pruned);
         }
     }
-    Ok(())
+
+    if let Some(status) = exit_status {
+        info!(%id, "process in shell {id} {status}");
+    }
+    Ok(exit_status)
+}
+
+/// Replace bare line feeds with CRLF in the content after `start`.
+fn translate_newlines(content: &mut String, start: usize) {
+    if !content[start..].contains('\n') {
+        return;
+    }
+    let added = content.split_off(start);
+    for c in added.chars() {
+        if c == '\n' && !content.ends_with('\r') {
+            content.push('\r');
+        }
+        content.push(c);
+    }
 }
 
 /// Find the last char boundary before an index in O(1) time.
@@ -150,13 +493,13 @@ fn prev_char_boundary(s: &str, i: usize) -> usize {
 async fn echo_task(
     id: Sid,
     encrypt: Encrypt,
+    mut seq: u64,
     mut shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
 ) -> Result<()> {
-    let mut seq = 0;
     while let Some(item) = shell_rx.recv().await {
         match item {
-            ShellData::Data(data) => {
+            ShellData::Data(data, _) => {
                 let msg = String::from_utf8_lossy(&data);
                 let term_data = TerminalData {
                     id: id.0,
@@ -164,12 +507,17 @@ async fn echo_task(
                         .segment(0x100000000 | id.0 as u64, seq, msg.as_bytes())
                         .into(),
                     seq,
+                    ..Default::default()
                 };
                 output_tx.send(ClientMessage::Data(term_data)).await?;
                 seq += msg.len() as u64;
             }
             ShellData::Sync(_) => (),
             ShellData::Size(_, _) => (),
+            ShellData::Mirror(_) => (),
+            ShellData::Record(_) => (),
+            ShellData::RateLimit(_) => (),
+            ShellData::Close(_) => break,
         }
     }
     Ok(())
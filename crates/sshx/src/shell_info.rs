@@ -0,0 +1,151 @@
+//! Details about what is running in a shell, for labeling its window.
+//!
+//! Titles and the working directory come from operating system command (OSC)
+//! escape sequences in the terminal output, which shells and editors commonly
+//! print. The foreground process is looked up from the terminal itself.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest escape sequence that is parsed, to bound memory use.
+const MAX_SEQUENCE_LEN: usize = 4096;
+
+/// Details about a shell, sent encrypted to viewers.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellInfo {
+    /// Window title, set by OSC 0 or 2.
+    pub title: Option<String>,
+    /// Working directory, set by OSC 7, with the home directory shortened.
+    pub cwd: Option<String>,
+    /// Name of the foreground process in the terminal.
+    pub process: Option<String>,
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Osc,
+    OscEscape,
+}
+
+/// Streaming parser for OSC sequences, which may be split across reads.
+#[derive(Debug, Default)]
+pub struct OscParser {
+    state: State,
+    sequence: String,
+}
+
+impl OscParser {
+    /// Construct a new parser.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read terminal output, updating the info. Returns true if it changed.
+    pub fn feed(&mut self, output: &str, info: &mut ShellInfo) -> bool {
+        let mut changed = false;
+        for c in output.chars() {
+            self.state = match (&self.state, c) {
+                (_, '\x1b') if !matches!(self.state, State::Osc) => State::Escape,
+                (State::Escape | State::OscEscape, ']') => {
+                    self.sequence.clear();
+                    State::Osc
+                }
+                (State::Osc, '\x07') | (State::OscEscape, '\\') => {
+                    changed |= apply_sequence(&self.sequence, info);
+                    State::Ground
+                }
+                (State::Osc, '\x1b') => State::OscEscape,
+                (State::Osc, c) if self.sequence.len() < MAX_SEQUENCE_LEN => {
+                    self.sequence.push(c);
+                    State::Osc
+                }
+                _ => State::Ground,
+            };
+        }
+        changed
+    }
+}
+
+/// Apply a complete OSC sequence, returning true if the info changed.
+fn apply_sequence(sequence: &str, info: &mut ShellInfo) -> bool {
+    let Some((command, arg)) = sequence.split_once(';') else {
+        return false;
+    };
+    let (field, value) = match command {
+        "0" | "2" => (&mut info.title, arg.to_string()),
+        "7" => match parse_file_url(arg) {
+            Some(path) => (&mut info.cwd, shorten_home(&path)),
+            None => return false,
+        },
+        _ => return false,
+    };
+    let value = (!value.is_empty()).then_some(value);
+    if *field == value {
+        return false;
+    }
+    *field = value;
+    true
+}
+
+/// Get the path from a `file://host/path` URL, decoding escaped characters.
+fn parse_file_url(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        let hex = |b: Option<u8>| (b? as char).to_digit(16);
+        if b == b'%' {
+            let (hi, lo) = (hex(iter.next())?, hex(iter.next())?);
+            bytes.push((hi * 16 + lo) as u8);
+        } else {
+            bytes.push(b);
+        }
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Replace the home directory at the start of a path with `~`.
+fn shorten_home(path: &str) -> String {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let home = home.trim_end_matches('/');
+    match path.strip_prefix(home) {
+        Some(rest) if !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+            format!("~{rest}")
+        }
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_and_cwd() {
+        let mut parser = OscParser::new();
+        let mut info = ShellInfo::default();
+        assert!(parser.feed("hi\x1b]0;vim\x07 there", &mut info));
+        assert_eq!(info.title.as_deref(), Some("vim"));
+
+        // Sequences may be split, and end with a string terminator.
+        assert!(!parser.feed("\x1b]7;file://host/tmp/a%20", &mut info));
+        assert!(parser.feed("b\x1b\\\x1b]2;vim\x1b\\", &mut info));
+        assert_eq!(info.cwd.as_deref(), Some("/tmp/a b"));
+        assert_eq!(info.title.as_deref(), Some("vim"));
+
+        // Other escape sequences are ignored.
+        assert!(!parser.feed("\x1b[1m\x1b]8;;https://sshx.io\x07", &mut info));
+    }
+
+    #[test]
+    fn file_urls() {
+        assert_eq!(parse_file_url("file:///"), Some("/".into()));
+        assert_eq!(parse_file_url("file://h/x%2Fy"), Some("/x/y".into()));
+        assert_eq!(parse_file_url("file://h/%zz"), None);
+        assert_eq!(parse_file_url("https://h/x"), None);
+    }
+}
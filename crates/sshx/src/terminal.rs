@@ -2,23 +2,161 @@
 
 #![allow(unsafe_code)]
 
+use std::fmt;
+use std::path::PathBuf;
+
 cfg_if::cfg_if! {
     if #[cfg(unix)] {
         mod unix;
-        pub use unix::{get_default_shell, Terminal};
+        #[cfg(target_os = "linux")]
+        mod cgroup;
+        #[cfg(target_os = "linux")]
+        mod sandbox;
+        pub use unix::{check_run_as, get_default_shell, Terminal};
     } else if #[cfg(windows)] {
         mod windows;
-        pub use windows::{get_default_shell, Terminal};
+        pub use windows::{check_run_as, get_default_shell, Terminal};
     } else {
         compile_error!("unsupported platform for terminal driver");
     }
 }
 
+/// A program to run inside of a terminal, along with its arguments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Command {
+    /// Name or path of the program, resolved through `PATH` if needed.
+    pub program: String,
+    /// Arguments passed to the program, not including its name.
+    pub args: Vec<String>,
+    /// Working directory of the process, if not inherited from this one.
+    pub cwd: Option<PathBuf>,
+    /// Extra environment variables to set for the process.
+    pub env: Vec<(String, String)>,
+    /// Isolate the process from the rest of the system, on Linux only.
+    pub sandbox: Option<Sandbox>,
+    /// Resource limits for the process and its children, on Unix only.
+    pub limits: Limits,
+    /// Name of an unprivileged account to run the process as, on Unix only.
+    /// This needs root, see [`check_run_as`]. The process then gets a clean
+    /// environment, with a default `PATH` and the variables of the account.
+    pub run_as: Option<String>,
+}
+
+/// Options for running a process in a sandbox, with a read-only view of the
+/// file system and its own writable `/tmp`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Also remove access to the network.
+    pub isolate_network: bool,
+}
+
+impl Command {
+    /// Construct a command that runs the given program with no arguments.
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Resource limits for a process, set with `setrlimit()` before it starts.
+///
+/// On Linux with a delegated cgroup v2 subtree, the memory and process limits
+/// apply to the shell as a whole instead, through a cgroup of its own. They
+/// only fall back to `setrlimit()` when that cgroup cannot enforce them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// CPU time of each process, in seconds.
+    pub cpu_secs: Option<u64>,
+    /// Memory of the shell, in bytes, or else the address space of each
+    /// process without cgroups.
+    pub memory: Option<u64>,
+    /// Number of processes in the shell, or else of the whole user without
+    /// cgroups.
+    pub processes: Option<u64>,
+    /// Size of each file that is written, in bytes.
+    pub file_size: Option<u64>,
+}
+
+impl Limits {
+    /// Returns true if no limits are set.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// How the process in a terminal ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process exited with this code.
+    Code(i32),
+    /// The process was terminated by the signal with this number.
+    Signal(i32),
+}
+
+impl ExitStatus {
+    /// Explain which resource limit ended the process, if any did.
+    pub fn exceeded_limit(&self) -> Option<&'static str> {
+        #[cfg(unix)]
+        match *self {
+            Self::Signal(nix::libc::SIGXCPU) => return Some("the CPU time limit was reached"),
+            Self::Signal(nix::libc::SIGXFSZ) => return Some("the file size limit was reached"),
+            _ => (),
+        }
+        None
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Code(code) => write!(f, "exited with status {code}"),
+            ExitStatus::Signal(signal) => write!(f, "was terminated by signal {signal}"),
+        }
+    }
+}
+
+/// How the process in a terminal ended after it was asked to terminate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// The process had already exited.
+    Exited,
+    /// The process exited within the grace period after it was hung up.
+    HungUp,
+    /// The process was still running after the grace period, so it was killed.
+    Killed,
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Termination::Exited => write!(f, "had already exited"),
+            Termination::HungUp => write!(f, "exited after it was hung up"),
+            Termination::Killed => write!(f, "was killed after the grace period"),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {arg}")?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
 
-    use super::Terminal;
+    #[cfg(unix)]
+    use super::Limits;
+    #[cfg(target_os = "linux")]
+    use super::Sandbox;
+    use super::{Command, ExitStatus, Terminal, Termination};
 
     #[tokio::test]
     async fn winsize() -> Result<()> {
@@ -29,4 +167,229 @@ mod tests {
         assert_eq!(terminal.get_winsize()?, (120, 72));
         Ok(())
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_args() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let command = Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), "echo \"$GREETING\" from $(pwd)".into()],
+            cwd: Some("/".into()),
+            env: vec![("GREETING".into(), "hello world".into())],
+            ..Default::default()
+        };
+        let mut terminal = Terminal::spawn(&command).await?;
+
+        let mut output = String::new();
+        let mut buf = [0u8; 1024];
+        while !output.contains('\n') {
+            let n = terminal.read(&mut buf).await?;
+            assert_ne!(n, 0, "terminal closed before printing a line");
+            output.push_str(std::str::from_utf8(&buf[..n])?);
+        }
+        assert_eq!(output.trim_end(), "hello world from /");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn exit_status() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let command = Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), "echo bye; exit 3".into()],
+            ..Default::default()
+        };
+        let mut terminal = Terminal::spawn(&command).await?;
+        let mut buf = [0u8; 1024];
+        while terminal.read(&mut buf).await? > 0 {}
+        assert_eq!(terminal.wait().await?, Some(ExitStatus::Code(3)));
+        Ok(())
+    }
+
+    /// Read from a terminal until its output contains some text.
+    #[cfg(unix)]
+    async fn read_until(terminal: &mut Terminal, text: &str) -> Result<String> {
+        use tokio::io::AsyncReadExt;
+
+        let mut output = String::new();
+        let mut buf = [0u8; 1024];
+        while !output.contains(text) {
+            let n = terminal.read(&mut buf).await?;
+            assert_ne!(n, 0, "terminal closed before printing {text:?}: {output}");
+            output.push_str(std::str::from_utf8(&buf[..n])?);
+        }
+        Ok(output)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn terminate_hangup() -> Result<()> {
+        use std::time::Duration;
+
+        let command = Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), "echo ready; exec sleep 100".into()],
+            ..Default::default()
+        };
+        let mut terminal = Terminal::spawn(&command).await?;
+        read_until(&mut terminal, "ready").await?;
+        let termination = terminal.terminate(Duration::from_secs(5)).await?;
+        assert_eq!(termination, Termination::HungUp);
+        assert_eq!(
+            terminal.terminate(Duration::ZERO).await?,
+            Termination::Exited
+        );
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn terminate_kill() -> Result<()> {
+        use std::time::{Duration, Instant};
+
+        // Both the shell and its background job ignore the hangup.
+        let script = "trap '' HUP; sleep 100 & echo job $!; while :; do sleep 1; done";
+        let command = Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), script.into()],
+            ..Default::default()
+        };
+        let mut terminal = Terminal::spawn(&command).await?;
+        let output = read_until(&mut terminal, "\r\n").await?;
+        let job: u32 = output.trim_start_matches("job ").trim_end().parse()?;
+
+        let start = Instant::now();
+        let termination = terminal.terminate(Duration::from_millis(300)).await?;
+        assert_eq!(termination, Termination::Killed);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // The orphaned job is killed too, though it may not have been reaped.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let stat = std::fs::read_to_string(format!("/proc/{job}/stat")).unwrap_or_default();
+        assert!(stat.is_empty() || stat.contains(") Z "), "{stat}");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn limits() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let path = std::env::temp_dir().join(format!("sshx-limits-{}", std::process::id()));
+        let script = format!(
+            "echo cpu $(ulimit -t); exec head -c 4096 /dev/zero > {}",
+            path.display()
+        );
+        let command = Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), script],
+            limits: Limits {
+                cpu_secs: Some(60),
+                file_size: Some(1024),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut terminal = Terminal::spawn(&command).await?;
+        let mut output = String::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = terminal.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            output.push_str(std::str::from_utf8(&buf[..n])?);
+        }
+        let written = std::fs::metadata(&path)?.len();
+        std::fs::remove_file(&path)?;
+
+        assert!(output.contains("cpu 60\r\n"), "{output}");
+        assert_eq!(written, 1024);
+        let status = terminal.wait().await?.unwrap();
+        assert_eq!(status, ExitStatus::Signal(nix::libc::SIGXFSZ));
+        assert!(status.exceeded_limit().is_some());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_as() -> Result<()> {
+        use nix::unistd::{geteuid, User};
+        use tokio::io::AsyncReadExt;
+
+        assert!(super::check_run_as("root").is_err());
+        let Some(user) = User::from_name("nobody")? else {
+            eprintln!("skipping test, there is no nobody user");
+            return Ok(());
+        };
+        if !geteuid().is_root() {
+            assert!(super::check_run_as("nobody").is_err());
+            eprintln!("skipping test, it needs to run as root");
+            return Ok(());
+        }
+
+        let command = Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), "echo $(id -u) $(id -G) $USER $HOME".into()],
+            run_as: Some("nobody".into()),
+            ..Default::default()
+        };
+        let mut terminal = Terminal::spawn(&command).await?;
+        let mut output = String::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = terminal.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            output.push_str(std::str::from_utf8(&buf[..n])?);
+        }
+        let expected = format!("{0} {1} nobody {2}", user.uid, user.gid, user.dir.display());
+        assert_eq!(output.trim_end(), expected);
+        assert_eq!(terminal.wait().await?, Some(ExitStatus::Code(0)));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn sandbox() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let script = "echo pid $$; touch /sshx-test || echo read-only; echo scratch > /tmp/test \
+                      && cat /tmp/test; echo interfaces $(grep -c : /proc/net/dev); exit 4";
+        let command = Command {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), script.into()],
+            sandbox: Some(Sandbox {
+                isolate_network: true,
+            }),
+            ..Default::default()
+        };
+        let mut terminal = Terminal::spawn(&command).await?;
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = terminal.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+        let output = String::from_utf8(output)?;
+        if output.contains("failed to enter sandbox") {
+            eprintln!("skipping test, user namespaces are not available: {output}");
+            return Ok(());
+        }
+
+        assert!(output.contains("pid 1\r\n"), "{output}");
+        assert!(output.contains("read-only\r\n"), "{output}");
+        assert!(output.contains("scratch\r\n"), "{output}");
+        assert!(output.contains("interfaces 1\r\n"), "{output}"); // only loopback
+        assert_eq!(terminal.wait().await?, Some(ExitStatus::Code(4)));
+        Ok(())
+    }
 }
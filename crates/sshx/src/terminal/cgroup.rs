@@ -0,0 +1,208 @@
+//! Control groups for shells on Linux, which limit all of their processes.
+//!
+//! This needs a delegated cgroup v2 subtree, like the one that
+//! `systemd-run --user --scope -p Delegate=yes sshx` runs in. The sshx process
+//! moves itself into a leaf cgroup, so that controllers can be enabled for the
+//! cgroups next to it, which each hold one shell.
+
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::unistd::write;
+use tracing::{debug, warn};
+
+use super::Limits;
+
+/// Controllers that are used for limits, if they are available.
+const CONTROLLERS: [&str; 2] = ["memory", "pids"];
+
+/// Cgroup of a single shell, which is removed when the shell ends.
+pub struct Cgroup {
+    path: PathBuf,
+    procs: File,
+    oom_kills: u64,
+    pids_max: u64,
+    /// Limits that were written to the cgroup's controllers.
+    enforced: Limits,
+}
+
+impl Cgroup {
+    /// Create a cgroup for a new shell, if any of its limits need one and
+    /// cgroups are available.
+    pub fn create(limits: &Limits) -> Option<Self> {
+        static BASE: OnceLock<Option<PathBuf>> = OnceLock::new();
+        if limits.memory.is_none() && limits.processes.is_none() {
+            return None;
+        }
+        let base = BASE.get_or_init(|| match delegate_subtree() {
+            Ok(base) => Some(base),
+            Err(err) => {
+                debug!(?err, "cgroups are not available for resource limits");
+                None
+            }
+        });
+        match Self::create_in(base.as_deref()?, limits) {
+            Ok(cgroup) => Some(cgroup),
+            Err(err) => {
+                warn!(?err, "failed to create cgroup for shell");
+                None
+            }
+        }
+    }
+
+    fn create_in(base: &Path, limits: &Limits) -> Result<Self> {
+        static COUNTER: AtomicU32 = AtomicU32::new(1);
+        let path = base.join(format!("shell-{}", COUNTER.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir(&path)?;
+        let mut enforced = Limits::default();
+        let result = (|| {
+            // Files are missing for controllers that are not available.
+            for (file, limit, slot) in [
+                ("memory.max", limits.memory, &mut enforced.memory),
+                ("pids.max", limits.processes, &mut enforced.processes),
+            ] {
+                let file = path.join(file);
+                if let Some(limit) = limit.filter(|_| file.exists()) {
+                    fs::write(file, limit.to_string())?;
+                    *slot = Some(limit);
+                }
+            }
+            if limits.memory.is_some() {
+                fs::write(path.join("memory.swap.max"), "0").ok(); // swap may be disabled
+            }
+            let procs = OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))?;
+            anyhow::Ok(procs)
+        })();
+        match result {
+            Ok(procs) => Ok(Self {
+                path,
+                procs,
+                oom_kills: 0,
+                pids_max: 0,
+                enforced,
+            }),
+            Err(err) => {
+                fs::remove_dir(&path).ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// Limits that still need to be set with `setrlimit()`, since this cgroup
+    /// does not enforce them for the shell as a whole.
+    pub fn fallback_limits(&self, limits: &Limits) -> Limits {
+        Limits {
+            memory: limits.memory.filter(|_| self.enforced.memory.is_none()),
+            processes: limits
+                .processes
+                .filter(|_| self.enforced.processes.is_none()),
+            ..limits.clone()
+        }
+    }
+
+    /// File that a child process writes to with [`Cgroup::join`].
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    /// Move the current process into a cgroup, after forking.
+    pub fn join(procs_fd: RawFd) -> Result<(), Errno> {
+        write(procs_fd, b"0").map(drop)
+    }
+
+    /// Check for limits that were reached since the last call.
+    pub fn events(&mut self) -> Vec<&'static str> {
+        let mut events = Vec::new();
+        let oom_kills = read_event(&self.path.join("memory.events"), "oom_kill");
+        if oom_kills > self.oom_kills {
+            self.oom_kills = oom_kills;
+            events.push("a process was killed for reaching the memory limit");
+        }
+        let pids_max = read_event(&self.path.join("pids.events"), "max");
+        if pids_max > self.pids_max {
+            self.pids_max = pids_max;
+            events.push("a process could not start because of the process limit");
+        }
+        events
+    }
+
+    /// Kill any processes left in the cgroup and remove it, which blocks.
+    pub fn remove(self) {
+        fs::write(self.path.join("cgroup.kill"), "1").ok();
+        for _ in 0..20 {
+            match fs::remove_dir(&self.path) {
+                Err(err) if err.raw_os_error() == Some(Errno::EBUSY as i32) => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(err) => {
+                    warn!(?err, "failed to remove cgroup of shell");
+                    return;
+                }
+                Ok(()) => return,
+            }
+        }
+        warn!(path = %self.path.display(), "cgroup of shell is still busy");
+    }
+}
+
+/// Prepare this process's cgroup to hold the cgroups of shells.
+///
+/// A cgroup with processes in it cannot enable controllers for its children,
+/// so this process first moves into a leaf cgroup of its own.
+fn delegate_subtree() -> Result<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let mount = mountinfo
+        .lines()
+        .find(|line| {
+            line.split(" - ")
+                .nth(1)
+                .is_some_and(|s| s.starts_with("cgroup2 "))
+        })
+        .and_then(|line| line.split(' ').nth(4))
+        .context("cgroup v2 is not mounted")?;
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    let cgroup = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .context("process is not in a cgroup v2 hierarchy")?;
+    let base = Path::new(mount).join(cgroup.trim_start_matches('/'));
+
+    let available = fs::read_to_string(base.join("cgroup.controllers"))?;
+    let enabled: Vec<_> = CONTROLLERS
+        .iter()
+        .filter(|name| available.split_whitespace().any(|c| c == **name))
+        .map(|name| format!("+{name}"))
+        .collect();
+    if enabled.is_empty() {
+        bail!("no memory or pids controllers in {}", base.display());
+    }
+
+    let leaf = base.join("sshx");
+    if !leaf.is_dir() {
+        fs::create_dir(&leaf)?;
+    }
+    fs::write(leaf.join("cgroup.procs"), "0")?;
+    fs::write(base.join("cgroup.subtree_control"), enabled.join(" "))?;
+    Ok(base)
+}
+
+/// Read a counter from a cgroup events file, or zero if it is missing.
+fn read_event(path: &Path, key: &str) -> u64 {
+    let events = fs::read_to_string(path).unwrap_or_default();
+    events
+        .lines()
+        .find_map(|line| match line.split_once(' ') {
+            Some((name, value)) if name == key => value.parse().ok(),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,217 @@
+//! Sandbox for shells on Linux, using unprivileged namespaces.
+//!
+//! The process gets new user, mount and PID namespaces, and optionally a new
+//! network namespace. Every mount is made read-only, with a fresh `/proc` for
+//! the new PID namespace and an empty, writable tmpfs at [`SCRATCH_DIR`].
+
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use anyhow::{bail, Context, Result};
+use close_fds::CloseFdsBuilder;
+use nix::errno::Errno;
+use nix::libc::{self, prctl, PR_SET_PDEATHSIG};
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{kill, signal, SigHandler, Signal};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, getpid, read, write, ForkResult, Gid, Pid, Uid};
+
+use super::Sandbox;
+
+/// Writable directory inside the sandbox, which is empty at the start.
+pub const SCRATCH_DIR: &str = "/tmp";
+
+/// Details for entering a sandbox, prepared before forking.
+pub struct SandboxSetup {
+    flags: CloneFlags,
+    uid_map: String,
+    gid_map: String,
+    mount_points: Vec<CString>,
+    /// Pipe that the child writes to once it is in the new namespaces.
+    unshared: (OwnedFd, OwnedFd),
+    /// Pipe that the parent writes to once it has mapped the child's user.
+    mapped: (OwnedFd, OwnedFd),
+}
+
+impl SandboxSetup {
+    /// Prepare to enter a sandbox, reading the current mounts. The user and
+    /// group are the ones that the process will have when it enters.
+    pub fn new(sandbox: &Sandbox, uid: Uid, gid: Gid) -> Result<Self> {
+        let mut flags = CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS;
+        flags |= CloneFlags::CLONE_NEWPID;
+        if sandbox.isolate_network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+        let mut mount_points = Vec::new();
+        for line in mountinfo.lines() {
+            if let Some(mount_point) = line.split(' ').nth(4) {
+                mount_points.push(CString::new(unescape_octal(mount_point))?);
+            }
+        }
+
+        // Map the user into the namespace as itself, so that files keep their
+        // owners and the shell has no capabilities once it starts.
+        Ok(Self {
+            flags,
+            uid_map: format!("{uid} {uid} 1"),
+            gid_map: format!("{gid} {gid} 1"),
+            mount_points,
+            unshared: pipe()?,
+            mapped: pipe()?,
+        })
+    }
+
+    /// Map the user into the namespace of a forked child, from the parent.
+    ///
+    /// The child waits for this in [`SandboxSetup::enter`]. A child that
+    /// dropped root privileges is not dumpable, so only a privileged parent
+    /// can write its maps, without exposing the child's memory.
+    pub fn map_user(self, child: Pid) -> Result<()> {
+        let (unshared, mapped) = (self.unshared.0, self.mapped.1);
+        // Close our end, so that reading stops if the child exits first.
+        drop(self.unshared.1);
+        if read(unshared.as_raw_fd(), &mut [0])? != 1 {
+            bail!("sandboxed process exited before entering its namespaces");
+        }
+        for (file, contents) in [
+            ("setgroups", "deny"),
+            ("uid_map", self.uid_map.as_str()),
+            ("gid_map", self.gid_map.as_str()),
+        ] {
+            std::fs::write(format!("/proc/{child}/{file}"), contents)
+                .with_context(|| format!("failed to write {file} of sandboxed process"))?;
+        }
+        write(mapped.as_raw_fd(), &[0])?;
+        Ok(())
+    }
+
+    /// Enter the sandbox from a forked child process, after it has become
+    /// the session leader of its terminal. The parent process must call
+    /// [`SandboxSetup::map_user`] at the same time.
+    ///
+    /// This forks again, since only children join the new PID namespace. The
+    /// sandboxed process returns, while this process waits for it and exits
+    /// with its status. It stays the session leader, so that it is sent
+    /// `SIGHUP` when the terminal closes, which the sandboxed process would
+    /// ignore as the init process of its namespace.
+    pub fn enter(&self) -> Result<(), Errno> {
+        unshare(self.flags)?;
+
+        // Wait for the parent to write our maps. It kills this process if it fails.
+        write(self.unshared.1.as_raw_fd(), &[0])?;
+        if read(self.mapped.0.as_raw_fd(), &mut [0])? != 1 {
+            return Err(Errno::EPERM);
+        }
+
+        // Safety: This process is single-threaded, since it was just forked.
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => {
+                // Safety: There are no other threads in this process, and it
+                // does not use any file descriptors other than standard I/O.
+                unsafe { CloseFdsBuilder::new().closefrom(3) };
+                for sig in [Signal::SIGHUP, Signal::SIGINT, Signal::SIGTERM] {
+                    // Safety: The default handler is always sound, and handlers
+                    // inherited from the parent do not work after forking.
+                    unsafe { signal(sig, SigHandler::SigDfl) }.ok();
+                }
+                let code = loop {
+                    match waitpid(child, None) {
+                        Err(Errno::EINTR) => continue,
+                        Ok(WaitStatus::Exited(_, code)) => break code,
+                        Ok(WaitStatus::Signaled(_, sig, _)) => {
+                            // Safety: The default handler is always sound.
+                            unsafe { signal(sig, SigHandler::SigDfl) }.ok();
+                            kill(getpid(), sig).ok();
+                            break 128 + sig as i32;
+                        }
+                        _ => break 1,
+                    }
+                };
+                std::process::exit(code);
+            }
+            ForkResult::Child => {}
+        }
+
+        // End the sandbox if the process waiting for it is killed.
+        // Safety: This only sets a flag on the current process.
+        Errno::result(unsafe { prctl(PR_SET_PDEATHSIG, Signal::SIGKILL as i32) })?;
+
+        let none = None::<&str>;
+        mount(none, "/", none, MsFlags::MS_REC | MsFlags::MS_PRIVATE, none)?;
+        for mount_point in &self.mount_points {
+            remount_read_only(mount_point)?;
+        }
+        let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+        mount(Some("tmpfs"), SCRATCH_DIR, Some("tmpfs"), flags, none)?;
+        let flags = flags | MsFlags::MS_NOEXEC;
+        mount(Some("proc"), "/proc", Some("proc"), flags, none)?;
+        Ok(())
+    }
+}
+
+/// Remount a mount point as read-only, keeping its other flags.
+///
+/// Flags like `nosuid` are locked in a user namespace and cannot be cleared,
+/// so they are read with `statvfs()` first.
+fn remount_read_only(mount_point: &CString) -> Result<(), Errno> {
+    let stat = match statvfs(mount_point.as_c_str()) {
+        Ok(stat) => stat,
+        // Hidden or inaccessible mounts cannot be reached from the sandbox.
+        Err(Errno::ENOENT | Errno::EACCES | Errno::EPERM) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if stat.flags().contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    let none = None::<&str>;
+    mount(none, mount_point.as_c_str(), none, flags, none)
+}
+
+/// Create a pipe that is closed in other programs that this process runs.
+fn pipe() -> Result<(OwnedFd, OwnedFd), Errno> {
+    let mut fds = [0; 2];
+    // Safety: The array has room for the two file descriptors of a pipe, which
+    // are then owned by nothing else.
+    unsafe {
+        Errno::result(libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC))?;
+        Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])))
+    }
+}
+
+/// Decode spaces and other characters escaped as `\NNN` in mountinfo.
+fn unescape_octal(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match code {
+            Some(code) if bytes[i] == b'\\' => {
+                result.push(code);
+                i += 4;
+            }
+            _ => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    result
+}
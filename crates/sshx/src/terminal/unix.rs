@@ -1,22 +1,93 @@
 use std::convert::Infallible;
 use std::env;
-use std::ffi::{CStr, CString};
+use std::ffi::{CString, OsStr, OsString};
 use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use close_fds::CloseFdsBuilder;
 use nix::errno::Errno;
-use nix::libc::{login_tty, TIOCGWINSZ, TIOCSWINSZ};
+use nix::libc::{login_tty, STDERR_FILENO, TIOCGWINSZ, TIOCSWINSZ};
 use nix::pty::{self, Winsize};
-use nix::sys::signal::{kill, Signal::SIGKILL};
-use nix::sys::wait::waitpid;
-use nix::unistd::{execvp, fork, ForkResult, Pid};
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{
+    chdir, execve, execvp, fork, geteuid, getgid, getuid, setgid, setuid, tcgetpgrp, ForkResult,
+    Gid, Pid, Uid, User,
+};
+#[cfg(not(target_vendor = "apple"))]
+use nix::unistd::{getgrouplist, setgroups};
 use pin_project::{pin_project, pinned_drop};
 use tokio::fs::{self, File};
 use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::time::{self, Duration, Instant};
 use tracing::{instrument, trace};
+#[cfg(not(target_os = "linux"))]
+use unsupported::{Cgroup, SandboxSetup};
+
+#[cfg(target_os = "linux")]
+use super::{cgroup::Cgroup, sandbox::SandboxSetup};
+use super::{Command, ExitStatus, Limits, Termination};
+
+/// Stand-ins for Linux namespaces and cgroups on other platforms.
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use std::os::fd::RawFd;
+
+    use anyhow::{bail, Result};
+    use nix::errno::Errno;
+    use nix::unistd::{Gid, Pid, Uid};
+
+    use super::super::{Limits, Sandbox};
+
+    pub enum SandboxSetup {}
+
+    impl SandboxSetup {
+        pub fn new(_: &Sandbox, _: Uid, _: Gid) -> Result<Self> {
+            bail!("sandboxed shells are only supported on Linux")
+        }
+
+        pub fn map_user(self, _: Pid) -> Result<()> {
+            match self {}
+        }
+
+        pub fn enter(&self) -> Result<(), Errno> {
+            match *self {}
+        }
+    }
+
+    pub enum Cgroup {}
+
+    impl Cgroup {
+        pub fn create(_: &Limits) -> Option<Self> {
+            None
+        }
+
+        pub fn fallback_limits(&self, _: &Limits) -> Limits {
+            match *self {}
+        }
+
+        pub fn procs_fd(&self) -> RawFd {
+            match *self {}
+        }
+
+        pub fn join(_: RawFd) -> Result<(), Errno> {
+            Ok(())
+        }
+
+        pub fn events(&mut self) -> Vec<&'static str> {
+            match *self {}
+        }
+
+        pub fn remove(self) {
+            match self {}
+        }
+    }
+}
 
 /// Returns the default shell on this system.
 pub async fn get_default_shell() -> String {
@@ -38,10 +109,114 @@ pub async fn get_default_shell() -> String {
     String::from("sh")
 }
 
+/// Check that shells can be run as another user, returning its login shell.
+///
+/// This fails if the user does not exist, or if privileges cannot be dropped
+/// to it because this process is not running as root.
+pub fn check_run_as(name: &str) -> Result<String> {
+    let account = Account::lookup(name)?;
+    Ok(account.shell)
+}
+
+/// Environment variables that describe the terminal to programs in it.
+const TERM_ENV: [(&str, &str); 3] = [
+    ("TERM", "xterm-256color"),
+    ("COLORTERM", "truecolor"),
+    ("TERM_PROGRAM", "sshx"),
+];
+
+/// Search path for shells that run as another user, which do not inherit the
+/// environment of this process.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Unprivileged account that a child process switches to before it starts.
+struct Account {
+    uid: Uid,
+    gid: Gid,
+    /// Supplementary groups, which are looked up before forking because that
+    /// can take locks in the C library.
+    groups: Vec<Gid>,
+    home: CString,
+    shell: String,
+    /// Environment variables that describe the account.
+    env: Vec<(&'static str, OsString)>,
+}
+
+impl Account {
+    fn lookup(name: &str) -> Result<Self> {
+        let Some(user) = User::from_name(name)? else {
+            bail!("user {name} does not exist");
+        };
+        if user.uid.is_root() {
+            bail!("refusing to run shells as root, which is not an unprivileged user");
+        }
+        if !geteuid().is_root() {
+            bail!("running shells as user {name} requires sshx to run as root");
+        }
+        let shell = match user.shell.to_str() {
+            Some(shell) if !shell.is_empty() => shell.to_string(),
+            _ => String::from("/bin/sh"),
+        };
+        #[cfg(not(target_vendor = "apple"))]
+        let groups = getgrouplist(&CString::new(name)?, user.gid)?;
+        // macOS can only list groups as part of initgroups(), which is not safe
+        // after forking, so shells keep the primary group alone there.
+        #[cfg(target_vendor = "apple")]
+        let groups = vec![user.gid];
+        Ok(Self {
+            uid: user.uid,
+            gid: user.gid,
+            groups,
+            home: CString::new(user.dir.as_os_str().as_encoded_bytes())?,
+            env: vec![
+                ("HOME", user.dir.into_os_string()),
+                ("USER", name.into()),
+                ("LOGNAME", name.into()),
+                ("SHELL", shell.clone().into()),
+            ],
+            shell,
+        })
+    }
+
+    /// Drop all privileges of the current process, after forking.
+    fn switch(&self) -> Result<(), Errno> {
+        #[cfg(not(target_vendor = "apple"))]
+        setgroups(&self.groups)?;
+        // Safety: The list has a single group, which has the same layout as gid_t.
+        #[cfg(target_vendor = "apple")]
+        Errno::result(unsafe { nix::libc::setgroups(1, self.groups.as_ptr().cast()) })?;
+        setgid(self.gid)?;
+        setuid(self.uid)?;
+        // Make sure that root privileges cannot be regained.
+        if setuid(Uid::from_raw(0)).is_ok() || geteuid() != self.uid {
+            return Err(Errno::EPERM);
+        }
+        Ok(())
+    }
+}
+
+/// Details for starting a child process, prepared before forking.
+struct ChildSetup {
+    program: CString,
+    argv: Vec<CString>,
+    cwd: Option<CString>,
+    account: Option<Account>,
+    sandbox: Option<SandboxSetup>,
+    cgroup_procs: Option<RawFd>,
+    /// Whole environment of the process, if it does not inherit ours.
+    env: Option<Vec<CString>>,
+    /// Limits that are set with `setrlimit()`, rather than by a cgroup.
+    limits: Limits,
+}
+
 /// An object that stores the state for a terminal session.
 #[pin_project(PinnedDrop)]
 pub struct Terminal {
     child: Pid,
+    /// Set once the child has exited and been waited for.
+    reaped: bool,
+    /// Cgroup that limits the child and its descendants, if available.
+    cgroup: Option<Cgroup>,
     #[pin]
     master_read: File,
     #[pin]
@@ -50,12 +225,24 @@ pub struct Terminal {
 
 impl Terminal {
     /// Create a new terminal, with attached PTY.
-    #[instrument]
     pub async fn new(shell: &str) -> Result<Terminal> {
+        Self::spawn(&Command::new(shell)).await
+    }
+
+    /// Create a new terminal running a command, with attached PTY.
+    #[instrument]
+    pub async fn spawn(command: &Command) -> Result<Terminal> {
+        if let Some(cwd) = &command.cwd {
+            if !fs::metadata(cwd).await.is_ok_and(|m| m.is_dir()) {
+                bail!("working directory {} does not exist", cwd.display());
+            }
+        }
+
         let result = pty::openpty(None, None)?;
+        let cgroup = Cgroup::create(&command.limits);
 
         // The slave file descriptor was created by openpty() and is forked here.
-        let child = Self::fork_child(shell, result.slave.as_raw_fd())?;
+        let child = Self::fork_child(command, cgroup.as_ref(), result.slave.as_raw_fd())?;
 
         // We need to clone the file object to prevent livelocks in Tokio, when multiple
         // reads and writes happen concurrently on the same file descriptor. This is a
@@ -68,41 +255,233 @@ impl Terminal {
 
         Ok(Self {
             child,
+            reaped: false,
+            cgroup,
             master_read,
             master_write,
         })
     }
 
     /// Entry point for the child process, which spawns a shell.
-    fn fork_child(shell: &str, slave_port: RawFd) -> Result<Pid> {
-        let shell = CString::new(shell.to_owned())?;
+    fn fork_child(command: &Command, cgroup: Option<&Cgroup>, slave_port: RawFd) -> Result<Pid> {
+        let mut program = CString::new(command.program.clone())?;
+        let mut argv = vec![program.clone()];
+        for arg in &command.args {
+            argv.push(CString::new(arg.clone())?);
+        }
+        let cwd = match &command.cwd {
+            Some(cwd) => Some(CString::new(cwd.as_os_str().as_encoded_bytes())?),
+            None => None,
+        };
+        let account = match &command.run_as {
+            Some(name) => Some(Account::lookup(name)?),
+            None => None,
+        };
+        // Shells that run as another user get an environment built from scratch,
+        // so that none of our variables leak into them.
+        let env = match &account {
+            Some(account) => {
+                let vars = clean_env(account, &command.env);
+                let path = vars.iter().rev().find(|(key, _)| key == "PATH");
+                let path = path.map_or(OsStr::new(""), |(_, value)| value.as_os_str());
+                let Some(found) = find_program(&command.program, path) else {
+                    bail!("could not find {} in PATH", command.program);
+                };
+                program = CString::new(found.into_os_string().into_encoded_bytes())?;
+                let mut env = Vec::with_capacity(vars.len());
+                for (key, value) in vars {
+                    let mut var = key.into_encoded_bytes();
+                    var.push(b'=');
+                    var.extend(value.into_encoded_bytes());
+                    env.push(CString::new(var)?);
+                }
+                Some(env)
+            }
+            None => None,
+        };
+        let sandbox = match &command.sandbox {
+            Some(sandbox) => {
+                // The sandbox maps in the user that the process runs as.
+                let (uid, gid) = match &account {
+                    Some(account) => (account.uid, account.gid),
+                    None => (getuid(), getgid()),
+                };
+                Some(SandboxSetup::new(sandbox, uid, gid)?)
+            }
+            None => None,
+        };
+        // Per-process rlimits are only a fallback for limits on the whole shell,
+        // since they break programs that reserve a lot of address space, and
+        // count every process of the user.
+        let limits = match cgroup {
+            Some(cgroup) => cgroup.fallback_limits(&command.limits),
+            None => command.limits.clone(),
+        };
+        let setup = ChildSetup {
+            program,
+            argv,
+            cwd,
+            account,
+            sandbox,
+            cgroup_procs: cgroup.map(Cgroup::procs_fd),
+            env,
+            limits,
+        };
 
         // Safety: This does not use any async-signal-unsafe operations in the child
         // branch, such as memory allocation.
         match unsafe { fork() }? {
-            ForkResult::Parent { child } => Ok(child),
-            ForkResult::Child => match Self::execv_child(&shell, slave_port) {
+            ForkResult::Parent { child } => {
+                if let Some(sandbox) = setup.sandbox {
+                    if let Err(err) = sandbox.map_user(child) {
+                        kill(child, Signal::SIGKILL).ok();
+                        waitpid(child, None).ok();
+                        return Err(err);
+                    }
+                }
+                Ok(child)
+            }
+            ForkResult::Child => match Self::execv_child(command, &setup, slave_port) {
                 Ok(infallible) => match infallible {},
                 Err(_) => std::process::exit(1),
             },
         }
     }
 
-    fn execv_child(shell: &CStr, slave_port: RawFd) -> Result<Infallible, Errno> {
+    fn execv_child(
+        command: &Command,
+        setup: &ChildSetup,
+        slave_port: RawFd,
+    ) -> Result<Infallible, Errno> {
         // Safety: The slave file descriptor was created by openpty().
         Errno::result(unsafe { login_tty(slave_port) })?;
+        if let Some(procs_fd) = setup.cgroup_procs {
+            Cgroup::join(procs_fd)?;
+        }
+        set_limits(&setup.limits)?;
+        if let Some(account) = &setup.account {
+            account
+                .switch()
+                .inspect_err(|err| report_child_error("failed to switch user", *err))?;
+        }
+        if let Some(sandbox) = &setup.sandbox {
+            sandbox
+                .enter()
+                .inspect_err(|err| report_child_error("failed to enter sandbox", *err))?;
+        }
         // Safety: This is called immediately before an execv(), and there are no other
         // threads in this process to interact with its file descriptor table.
         unsafe { CloseFdsBuilder::new().closefrom(3) };
 
         // Set terminal environment variables appropriately.
-        env::set_var("TERM", "xterm-256color");
-        env::set_var("COLORTERM", "truecolor");
-        env::set_var("TERM_PROGRAM", "sshx");
-        env::remove_var("TERM_PROGRAM_VERSION");
+        if setup.env.is_none() {
+            for (key, value) in TERM_ENV {
+                env::set_var(key, value);
+            }
+            env::remove_var("TERM_PROGRAM_VERSION");
+            for (key, value) in &command.env {
+                env::set_var(key, value);
+            }
+        }
+
+        match (&setup.cwd, &setup.account) {
+            (Some(cwd), _) => chdir(cwd.as_c_str())?,
+            // Start in the home directory, since ours may not be accessible.
+            (None, Some(account)) => chdir(account.home.as_c_str()).unwrap_or(()),
+            (None, None) => (),
+        }
 
         // Start the process.
-        execvp(shell, &[shell])
+        match &setup.env {
+            Some(env) => execve(&setup.program, &setup.argv, env),
+            None => execvp(&setup.program, &setup.argv),
+        }
+    }
+
+    /// Wait for the process to exit, after its output has ended.
+    ///
+    /// Returns `None` if it is still running after a short time, which happens
+    /// when it closes the terminal without exiting.
+    pub async fn wait(&mut self) -> Result<Option<ExitStatus>> {
+        for _ in 0..20 {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(None)
+    }
+
+    /// Check if the process has exited, without blocking.
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if self.reaped {
+            return Ok(None);
+        }
+        let status = match waitpid(self.child, Some(WaitPidFlag::WNOHANG))? {
+            WaitStatus::Exited(_, code) => ExitStatus::Code(code),
+            WaitStatus::Signaled(_, signal, _) => ExitStatus::Signal(signal as i32),
+            _ => return Ok(None),
+        };
+        self.reaped = true;
+        Ok(Some(status))
+    }
+
+    /// End the process like closing a terminal window does, killing it if it
+    /// is still running after a grace period.
+    ///
+    /// This sends `SIGHUP` to the foreground process group and to the group of
+    /// the shell, so that editors can save their buffers and the shell can hang
+    /// up its jobs. Any process left in those groups is sent `SIGKILL`.
+    pub async fn terminate(&mut self, grace: Duration) -> Result<Termination> {
+        if self.reaped || self.try_wait()?.is_some() {
+            return Ok(Termination::Exited);
+        }
+        let groups = process_groups(self.child, &self.master_read);
+        for &pgrp in &groups {
+            killpg(pgrp, Signal::SIGHUP).ok();
+            // Stopped jobs only see SIGHUP once they are resumed.
+            killpg(pgrp, Signal::SIGCONT).ok();
+        }
+
+        let deadline = Instant::now() + grace;
+        loop {
+            self.try_wait()?;
+            let running = groups.iter().any(|&pgrp| killpg(pgrp, None).is_ok());
+            if self.reaped && !running {
+                return Ok(Termination::HungUp);
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+
+        trace!(child = %self.child, "killing terminal after the grace period");
+        for &pgrp in &groups {
+            killpg(pgrp, Signal::SIGKILL).ok();
+        }
+        if !self.reaped {
+            kill(self.child, Signal::SIGKILL).ok();
+            while self.try_wait()?.is_none() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        Ok(Termination::Killed)
+    }
+
+    /// Get the name of the foreground process in the terminal.
+    ///
+    /// This reads the process name from `/proc`, so it is only known on Linux.
+    pub fn foreground_process(&self) -> Option<String> {
+        let pgrp = tcgetpgrp(self.master_read.as_raw_fd()).ok()?;
+        let comm = std::fs::read_to_string(format!("/proc/{pgrp}/comm")).ok()?;
+        Some(comm.trim_end().to_string())
+    }
+
+    /// Check for resource limits of the cgroup that were reached since the
+    /// last call, which are not otherwise reported.
+    pub fn limit_events(&mut self) -> Vec<&'static str> {
+        self.cgroup.as_mut().map(Cgroup::events).unwrap_or_default()
     }
 
     /// Get the window size of the TTY.
@@ -131,7 +510,14 @@ impl AsyncRead for Terminal {
         cx: &mut Context<'_>,
         buf: &mut io::ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        self.project().master_read.poll_read(cx, buf)
+        match self.project().master_read.poll_read(cx, buf) {
+            // Linux reports EIO once every process has closed the terminal,
+            // which is the end of its output.
+            Poll::Ready(Err(err)) if err.raw_os_error() == Some(Errno::EIO as i32) => {
+                Poll::Ready(Ok(()))
+            }
+            poll => poll,
+        }
     }
 }
 
@@ -159,18 +545,107 @@ impl PinnedDrop for Terminal {
     fn drop(self: Pin<&mut Self>) {
         let this = self.project();
         let child = *this.child;
+        let cgroup = this.cgroup.take();
         trace!(%child, "dropping terminal");
+        if *this.reaped {
+            // The process ID may already belong to another process.
+            if let Some(cgroup) = cgroup {
+                std::thread::spawn(move || cgroup.remove());
+            }
+            return;
+        }
 
-        // Kill the child process on closure so that it doesn't keep running.
-        kill(child, SIGKILL).ok();
+        // Kill the child process on closure so that it doesn't keep running, along
+        // with the jobs in its terminal.
+        for pgrp in process_groups(child, &this.master_read) {
+            killpg(pgrp, Signal::SIGKILL).ok();
+        }
+        kill(child, Signal::SIGKILL).ok();
 
         // Reap the zombie process in a background thread.
         std::thread::spawn(move || {
             waitpid(child, None).ok();
+            if let Some(cgroup) = cgroup {
+                cgroup.remove();
+            }
         });
     }
 }
 
+/// Process groups that belong to a terminal: the group of its child, which is
+/// a session leader, and the foreground group if that is different.
+fn process_groups(child: Pid, master: &File) -> Vec<Pid> {
+    let mut groups = vec![child];
+    if let Ok(pgrp) = tcgetpgrp(master.as_raw_fd()) {
+        if pgrp != child && pgrp.as_raw() > 0 {
+            groups.push(pgrp);
+        }
+    }
+    groups
+}
+
+/// Explain why a child process failed to start in its terminal, since it has
+/// no logs.
+///
+/// This runs after forking, so it writes each part separately rather than
+/// allocating a message.
+fn report_child_error(context: &str, err: Errno) {
+    for part in ["sshx: ", context, ": ", err.desc(), "\r\n"] {
+        nix::unistd::write(STDERR_FILENO, part.as_bytes()).ok();
+    }
+}
+
+/// Build the whole environment of a shell that runs as another user, with the
+/// extra variables from its command last.
+fn clean_env(account: &Account, extra: &[(String, String)]) -> Vec<(OsString, OsString)> {
+    let mut vars: Vec<(OsString, OsString)> = TERM_ENV
+        .into_iter()
+        .map(|(key, value)| (key.into(), value.into()))
+        .collect();
+    vars.push(("PATH".into(), DEFAULT_PATH.into()));
+    for (key, value) in &account.env {
+        vars.push(((*key).into(), value.clone()));
+    }
+    for (key, value) in extra {
+        vars.retain(|(k, _)| k != key.as_str());
+        vars.push((key.into(), value.into()));
+    }
+    vars
+}
+
+/// Find a program in a search path, like `execvp()` does with the environment
+/// of the current process.
+fn find_program(program: &str, path: &OsStr) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(program.into());
+    }
+    env::split_paths(path)
+        .map(|dir| dir.join(program))
+        .find(|file| match file.metadata() {
+            Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        })
+}
+
+/// Apply resource limits to the current process, after forking.
+fn set_limits(limits: &Limits) -> Result<(), Errno> {
+    if let Some(secs) = limits.cpu_secs {
+        // The process is sent SIGXCPU at the soft limit, and killed at the hard
+        // limit, which would come first if they were equal.
+        setrlimit(Resource::RLIMIT_CPU, secs, secs.saturating_add(1))?;
+    }
+    for (resource, limit) in [
+        (Resource::RLIMIT_AS, limits.memory),
+        (Resource::RLIMIT_NPROC, limits.processes),
+        (Resource::RLIMIT_FSIZE, limits.file_size),
+    ] {
+        if let Some(limit) = limit {
+            setrlimit(resource, limit, limit)?;
+        }
+    }
+    Ok(())
+}
+
 fn make_winsize(rows: u16, cols: u16) -> Winsize {
     Winsize {
         ws_row: rows,
@@ -1,14 +1,17 @@
 use std::pin::Pin;
-use std::process::Command;
+use std::process;
 use std::task::Context;
 use std::task::Poll;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use pin_project::{pin_project, pinned_drop};
 use tokio::fs::{self, File};
 use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::time::Duration;
 use tracing::instrument;
 
+use super::{Command, ExitStatus, Termination};
+
 /// Returns the default shell on this system.
 ///
 /// For Windows, this is implemented currently to just look for shells at a
@@ -29,6 +32,12 @@ pub async fn get_default_shell() -> String {
     String::from("cmd.exe")
 }
 
+/// Check that shells can be run as another user, which is not supported on
+/// Windows.
+pub fn check_run_as(_name: &str) -> Result<String> {
+    bail!("running shells as another user is only supported on Unix")
+}
+
 /// An object that stores the state for a terminal session.
 #[pin_project(PinnedDrop)]
 pub struct Terminal {
@@ -42,15 +51,34 @@ pub struct Terminal {
 
 impl Terminal {
     /// Create a new terminal, with attached PTY.
-    #[instrument]
     pub async fn new(shell: &str) -> Result<Terminal> {
-        let mut command = Command::new(shell);
+        Self::spawn(&Command::new(shell)).await
+    }
+
+    /// Create a new terminal running a command, with attached PTY.
+    #[instrument]
+    pub async fn spawn(spec: &Command) -> Result<Terminal> {
+        if spec.sandbox.is_some() {
+            bail!("sandboxed shells are only supported on Linux");
+        }
+        if !spec.limits.is_empty() {
+            bail!("resource limits are only supported on Unix");
+        }
+        if spec.run_as.is_some() {
+            bail!("running shells as another user is only supported on Unix");
+        }
+        let mut command = process::Command::new(&spec.program);
+        command.args(&spec.args);
+        if let Some(cwd) = &spec.cwd {
+            command.current_dir(cwd);
+        }
 
         // Set terminal environment variables appropriately.
         command.env("TERM", "xterm-256color");
         command.env("COLORTERM", "truecolor");
         command.env("TERM_PROGRAM", "sshx");
         command.env_remove("TERM_PROGRAM_VERSION");
+        command.envs(spec.env.iter().map(|(k, v)| (k, v)));
 
         let mut child =
             tokio::task::spawn_blocking(move || conpty::Process::spawn(command)).await??;
@@ -65,6 +93,36 @@ impl Terminal {
         })
     }
 
+    /// Wait for the process to exit, after its output has ended.
+    ///
+    /// Returns `None` if it is still running after a short time.
+    pub async fn wait(&mut self) -> Result<Option<ExitStatus>> {
+        let code = self.child.wait(Some(1000)).ok();
+        Ok(code.map(|code| ExitStatus::Code(code as i32)))
+    }
+
+    /// End the process, which is killed right away since Windows has no
+    /// equivalent of hanging up a terminal.
+    pub async fn terminate(&mut self, _grace: Duration) -> Result<Termination> {
+        if self.child.wait(Some(0)).is_ok() {
+            return Ok(Termination::Exited);
+        }
+        self.child.exit(0)?;
+        Ok(Termination::Killed)
+    }
+
+    /// Get the name of the foreground process in the terminal, which is not
+    /// known on Windows.
+    pub fn foreground_process(&self) -> Option<String> {
+        None
+    }
+
+    /// Check for resource limits that were reached, which are not supported
+    /// on Windows.
+    pub fn limit_events(&mut self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
     /// Get the window size of the TTY.
     pub fn get_winsize(&self) -> Result<(u16, u16)> {
         Ok(self.winsize)
@@ -0,0 +1,473 @@
+//! Shells backed by the windows of an existing tmux session.
+//!
+//! A single client is attached to the session in control mode (`tmux -C`),
+//! which reports the output of every pane and accepts commands to type into,
+//! resize, open and close windows. See
+//! <https://github.com/tmux/tmux/wiki/Control-Mode> for the protocol.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{bail, ensure, Context, Result};
+use sshx_core::proto::{client_update::ClientMessage, ShellPosition};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+/// Handle to a tmux session, shared by the shells that show its windows.
+#[derive(Clone)]
+pub struct TmuxSession {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    name: String,
+    /// ID of the session, like `$0`, which stays the same if it is renamed.
+    id: String,
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    state: Mutex<State>,
+    _child: Child,
+}
+
+#[derive(Default)]
+struct State {
+    /// Commands written to tmux, waiting for their replies in order.
+    replies: VecDeque<Reply>,
+    /// Windows that are shown as shells, with the pane displayed in each.
+    windows: HashMap<String, String>,
+    /// Channels for the output of the displayed panes.
+    panes: HashMap<String, mpsc::UnboundedSender<Vec<u8>>>,
+    /// Windows that are not shown yet, with their active pane, oldest first.
+    unclaimed: VecDeque<(String, String)>,
+    /// Asks the server to open a shell for each new window, once set.
+    announce_tx: Option<mpsc::Sender<ClientMessage>>,
+    /// Set when the control client has exited.
+    exited: bool,
+}
+
+struct Reply {
+    tx: oneshot::Sender<Result<Vec<String>, String>>,
+    hook: Hook,
+}
+
+/// Change to the state made as soon as a command is answered, so that it is
+/// ordered with the output and notifications around it.
+enum Hook {
+    None,
+    /// Mark the window listed as `WINDOW PANE` in the reply as shown.
+    Claim,
+    /// Send the output of a pane to a shell from now on.
+    Watch(String, mpsc::UnboundedSender<Vec<u8>>),
+    /// Offer the windows listed as `WINDOW PANE` in the reply as new shells.
+    Offer,
+}
+
+impl fmt::Debug for TmuxSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TmuxSession")
+            .field(&self.inner.name)
+            .finish()
+    }
+}
+
+impl TmuxSession {
+    /// Attach to the tmux session with a name, which must already exist.
+    pub async fn attach(name: &str) -> Result<Self> {
+        let output = Command::new("tmux")
+            .args(["display-message", "-p", "-t", &format!("={name}:")])
+            .arg("#{session_id}")
+            .stderr(Stdio::null())
+            .output()
+            .await
+            .context("failed to run tmux, is it installed?")?;
+        let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        ensure!(
+            output.status.success() && !id.is_empty(),
+            "no tmux session named {name}"
+        );
+
+        let mut child = Command::new("tmux")
+            .args(["-C", "attach-session", "-t", &id])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+
+        let session = Self {
+            inner: Arc::new(Inner {
+                name: name.into(),
+                id,
+                stdin: tokio::sync::Mutex::new(stdin),
+                state: Mutex::new(State::default()),
+                _child: child,
+            }),
+        };
+        tokio::spawn(read_events(Arc::downgrade(&session.inner), stdout));
+
+        let list = format!(
+            "list-windows -t '{}' -F '{WINDOW_FORMAT}'",
+            session.inner.id
+        );
+        session.command(&list, Hook::Offer).await?;
+        debug!(session = name, "attached to tmux");
+        Ok(session)
+    }
+
+    /// Returns the name of the session.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Ask the server to open a shell for each window, including those that
+    /// are created later, by sending [`ClientMessage::CreateShell`].
+    pub fn announce_windows(&self, output_tx: mpsc::Sender<ClientMessage>) {
+        let mut state = self.inner.state.lock().unwrap();
+        for _ in 0..state.unclaimed.len() {
+            output_tx.try_send(create_shell()).ok();
+        }
+        state.announce_tx = Some(output_tx);
+    }
+
+    /// Show a window as a shell, taking the oldest window that is not shown
+    /// yet, or else opening a new one.
+    pub async fn open(&self) -> Result<TmuxWindow> {
+        let existing = {
+            let mut state = self.inner.state.lock().unwrap();
+            let existing = state.unclaimed.pop_front();
+            if let Some((window, pane)) = &existing {
+                state.windows.insert(window.clone(), pane.clone());
+            }
+            existing
+        };
+        let (window, pane) = match existing {
+            Some(ids) => ids,
+            None => {
+                let cmd = format!(
+                    "new-window -d -t '{}:' -P -F '{WINDOW_FORMAT}'",
+                    self.inner.id
+                );
+                let reply = self.command(&cmd, Hook::Claim).await?;
+                parse_ids(reply.first()).context("unexpected reply to new-window")?
+            }
+        };
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+        let mut shown = TmuxWindow {
+            session: self.clone(),
+            window,
+            pane,
+            output_rx,
+            pending: Vec::new(),
+        };
+
+        // Windows have the same initial size as other shells.
+        shown.resize(24, 80).await?;
+        let cursor_cmd = format!(
+            "display-message -p -t {} '#{{cursor_x}} #{{cursor_y}}'",
+            shown.pane
+        );
+        let cursor = self.command(&cursor_cmd, Hook::None).await?;
+        let capture = format!("capture-pane -p -e -t {}", shown.pane);
+        let hook = Hook::Watch(shown.pane.clone(), output_tx);
+        let mut screen = self.command(&capture, hook).await?;
+
+        // Redraw the current screen, since the output that led to it is gone.
+        while screen.last().is_some_and(|line| line.is_empty()) {
+            screen.pop();
+        }
+        shown.pending = screen.join("\r\n").into_bytes();
+        if let Some((x, y)) = cursor.first().and_then(|pos| pos.split_once(' ')) {
+            let (x, y): (u32, u32) = (x.parse()?, y.parse()?);
+            shown
+                .pending
+                .extend(format!("\x1b[{};{}H", y + 1, x + 1).as_bytes());
+        }
+        debug!(
+            window = shown.window,
+            pane = shown.pane,
+            "showing tmux window"
+        );
+        Ok(shown)
+    }
+
+    /// Run a command in tmux, returning the lines that it printed.
+    async fn command(&self, cmd: &str, hook: Hook) -> Result<Vec<String>> {
+        let (tx, rx) = oneshot::channel();
+        {
+            // Replies are in the same order as the commands that are written.
+            let mut stdin = self.inner.stdin.lock().await;
+            {
+                let mut state = self.inner.state.lock().unwrap();
+                ensure!(!state.exited, "tmux session {} has ended", self.inner.name);
+                state.replies.push_back(Reply { tx, hook });
+            }
+            stdin.write_all(format!("{cmd}\n").as_bytes()).await?;
+        }
+        match rx.await {
+            Ok(Ok(lines)) => Ok(lines),
+            Ok(Err(err)) => bail!("tmux: {err}"),
+            Err(_) => bail!("tmux session {} has ended", self.inner.name),
+        }
+    }
+}
+
+/// Format of a window and its active pane, parsed by [`parse_ids`].
+const WINDOW_FORMAT: &str = "#{window_id} #{pane_id}";
+
+fn parse_ids(line: Option<&String>) -> Option<(String, String)> {
+    let (window, pane) = line?.split_once(' ')?;
+    Some((window.into(), pane.into()))
+}
+
+fn create_shell() -> ClientMessage {
+    ClientMessage::CreateShell(ShellPosition { x: 0, y: 0 })
+}
+
+/// Window of a tmux session that is shown as a shell.
+///
+/// Dropping this stops showing the window, but leaves it open in tmux.
+pub struct TmuxWindow {
+    session: TmuxSession,
+    window: String,
+    pane: String,
+    output_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Output that has been received but not read yet.
+    pending: Vec<u8>,
+}
+
+impl TmuxWindow {
+    /// Read output from the window's pane, returning 0 once it is closed.
+    pub async fn read(&mut self, buf: &mut [u8]) -> usize {
+        if self.pending.is_empty() {
+            match self.output_rx.recv().await {
+                Some(data) => self.pending = data,
+                None => return 0,
+            }
+        }
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+
+    /// Type input into the window's pane.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(256) {
+            let mut cmd = format!("send-keys -t {} -H", self.pane);
+            for byte in chunk {
+                cmd += &format!(" {byte:02x}");
+            }
+            self.session.command(&cmd, Hook::None).await?;
+        }
+        Ok(())
+    }
+
+    /// Change the size of the window.
+    pub async fn resize(&self, rows: u16, cols: u16) -> Result<()> {
+        let cmd = format!("resize-window -t {} -x {cols} -y {rows}", self.window);
+        self.session.command(&cmd, Hook::None).await?;
+        Ok(())
+    }
+
+    /// Close the window in tmux.
+    pub async fn kill(&self) -> Result<()> {
+        let cmd = format!("kill-window -t {}", self.window);
+        self.session.command(&cmd, Hook::None).await?;
+        Ok(())
+    }
+}
+
+impl Drop for TmuxWindow {
+    fn drop(&mut self) {
+        let mut state = self.session.inner.state.lock().unwrap();
+        state.windows.remove(&self.window);
+        state.panes.remove(&self.pane);
+        if state.exited {
+            return;
+        }
+        drop(state);
+
+        // Resizing fixed the size of the window, so let tmux manage it again.
+        let session = self.session.clone();
+        let cmd = format!("set-option -w -u -t {} window-size", self.window);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move { session.command(&cmd, Hook::None).await.ok() });
+        }
+    }
+}
+
+/// Handle notifications and replies from the control client until it exits.
+async fn read_events(inner: Weak<Inner>, stdout: ChildStdout) {
+    let mut lines = BufReader::new(stdout).split(b'\n');
+    // Reply that is being read: its `%begin` line, and whether it is for us.
+    let mut block: Option<(String, bool)> = None;
+    let mut reply = Vec::new();
+
+    while let Ok(Some(line)) = lines.next_segment().await {
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        if let Some(rest) = line.strip_prefix(b"%output ") {
+            if let Some(space) = rest.iter().position(|&b| b == b' ') {
+                let pane = String::from_utf8_lossy(&rest[..space]);
+                let state = inner.state.lock().unwrap();
+                if let Some(output_tx) = state.panes.get(&*pane) {
+                    output_tx.send(unescape(&rest[space + 1..])).ok();
+                }
+            }
+            continue;
+        }
+
+        let line = String::from_utf8_lossy(&line).into_owned();
+        if let Some((begin, ours)) = &block {
+            // Replies end with the same time, number and flags as they began.
+            let result = match line.split_once(' ') {
+                Some(("%end", rest)) if rest == begin => Ok(std::mem::take(&mut reply)),
+                Some(("%error", rest)) if rest == begin => {
+                    Err(std::mem::take(&mut reply).join("\n"))
+                }
+                _ => {
+                    reply.push(line);
+                    continue;
+                }
+            };
+            if *ours {
+                inner.finish_reply(result);
+            }
+            block = None;
+            reply.clear();
+            continue;
+        }
+
+        let mut words = line.split(' ');
+        match words.next() {
+            Some("%begin") => {
+                let begin = line["%begin ".len()..].to_string();
+                // The last field is 1 for commands sent by this client.
+                let ours = begin.ends_with(" 1");
+                block = Some((begin, ours));
+            }
+            Some("%window-add") => {
+                if let Some(window) = words.next() {
+                    let session = TmuxSession { inner };
+                    let cmd = format!("display-message -p -t {window} '{WINDOW_FORMAT}'");
+                    tokio::spawn(async move { session.command(&cmd, Hook::Offer).await.ok() });
+                }
+            }
+            Some("%window-close" | "%unlinked-window-close") => {
+                if let Some(window) = words.next() {
+                    inner.close_window(window);
+                }
+            }
+            Some("%exit") => break,
+            _ => (),
+        }
+    }
+
+    if let Some(inner) = inner.upgrade() {
+        warn!(session = inner.name, "tmux control client exited");
+        let mut state = inner.state.lock().unwrap();
+        state.exited = true;
+        state.replies.clear();
+        state.panes.clear();
+        state.unclaimed.clear();
+    }
+}
+
+impl Inner {
+    fn finish_reply(&self, result: Result<Vec<String>, String>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(Reply { tx, hook }) = state.replies.pop_front() else {
+            warn!("received a reply from tmux with no command");
+            return;
+        };
+        if let Ok(lines) = &result {
+            match hook {
+                Hook::None => (),
+                Hook::Claim => {
+                    if let Some((window, pane)) = parse_ids(lines.first()) {
+                        state.windows.insert(window, pane);
+                    }
+                }
+                Hook::Watch(pane, output_tx) => {
+                    state.panes.insert(pane, output_tx);
+                }
+                Hook::Offer => {
+                    for (window, pane) in lines.iter().filter_map(|line| parse_ids(Some(line))) {
+                        let known = state.windows.contains_key(&window)
+                            || state.unclaimed.iter().any(|(w, _)| *w == window);
+                        if !known {
+                            if let Some(announce_tx) = &state.announce_tx {
+                                announce_tx.try_send(create_shell()).ok();
+                            }
+                            state.unclaimed.push_back((window, pane));
+                        }
+                    }
+                }
+            }
+        }
+        tx.send(result).ok();
+    }
+
+    fn close_window(&self, window: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pane) = state.windows.remove(window) {
+            // Ends the shell showing this window.
+            state.panes.remove(&pane);
+        }
+        state.unclaimed.retain(|(w, _)| w != window);
+    }
+}
+
+/// Decode output from tmux, which escapes control characters and backslashes
+/// as octal, like `\015`.
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let octal = data
+            .get(i + 1..i + 4)
+            .filter(|digits| digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match octal {
+            Some(digits) if data[i] == b'\\' => {
+                let value = digits.iter().fold(0u32, |n, d| n * 8 + (d - b'0') as u32);
+                out.push(value as u8);
+                i += 4;
+            }
+            _ => {
+                out.push(data[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ids, unescape};
+
+    #[test]
+    fn unescape_output() {
+        assert_eq!(unescape(br"echo hi\015\012"), b"echo hi\r\n");
+        assert_eq!(unescape(br"\033[?2004h$ "), b"\x1b[?2004h$ ");
+        assert_eq!(unescape(br"a\134b"), b"a\\b");
+        assert_eq!(
+            unescape("caf\u{e9} \\01".as_bytes()),
+            "caf\u{e9} \\01".as_bytes()
+        );
+    }
+
+    #[test]
+    fn parse_window_ids() {
+        let line = String::from("@3 %5");
+        assert_eq!(parse_ids(Some(&line)), Some(("@3".into(), "%5".into())));
+        assert_eq!(parse_ids(Some(&String::from("@3"))), None);
+        assert_eq!(parse_ids(None), None);
+    }
+}
@@ -0,0 +1,362 @@
+//! Terminal-native client that joins an existing session, without a browser.
+
+use std::io::{self as std_io, Write};
+
+use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use sshx_core::ws::{WsChunk, WsClient, WsServer, WsWinsize};
+use sshx_core::{Sid, Uid};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, warn};
+
+use crate::compress::decode_chunk;
+use crate::console::{get_winsize, read_stdin, RawMode};
+use crate::encrypt::Encrypt;
+
+/// Byte sent by the local terminal for Ctrl-], which detaches the viewer.
+const DETACH_KEY: u8 = 0x1d;
+
+/// The parts of a session link, as printed by the sshx client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionUrl {
+    /// Origin of the web server, like `https://sshx.io`.
+    pub origin: String,
+    /// Name of the session.
+    pub name: String,
+    /// Encryption key, from the URL fragment.
+    pub key: String,
+    /// Write password, if this is a writable link.
+    pub write_password: Option<String>,
+}
+
+impl SessionUrl {
+    /// Parse a link of the form `https://host/s/NAME#KEY[,WRITE_PASSWORD]`.
+    pub fn parse(url: &str) -> Result<Self> {
+        let (base, fragment) = url
+            .split_once('#')
+            .context("session link is missing the encryption key")?;
+        let (origin, name) = base
+            .trim_end_matches('/')
+            .rsplit_once("/s/")
+            .context("session link should have the form https://host/s/NAME#KEY")?;
+        if !(origin.starts_with("http://") || origin.starts_with("https://")) {
+            bail!("session link should start with http:// or https://");
+        }
+        if name.is_empty() || name.contains('/') {
+            bail!("invalid session name in link");
+        }
+        let (key, write_password) = match fragment.split_once(',') {
+            Some((key, password)) => (key, Some(password.to_string())),
+            None => (fragment, None),
+        };
+        if key.is_empty() {
+            bail!("session link is missing the encryption key");
+        }
+        Ok(Self {
+            origin: origin.into(),
+            name: name.into(),
+            key: key.into(),
+            write_password,
+        })
+    }
+
+    /// Returns the WebSocket endpoint of the session on the server.
+    pub fn ws_endpoint(&self) -> String {
+        let origin = match self.origin.strip_prefix("http") {
+            Some(rest) => format!("ws{rest}"),
+            None => self.origin.clone(),
+        };
+        format!("{origin}/api/s/{}", self.name)
+    }
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// State of a viewer connected to a session over WebSocket.
+struct Viewer {
+    socket: Socket,
+    encrypt: Encrypt,
+    user_id: Uid,
+    can_write: bool,
+    /// Shell being displayed, with its last known window position and size.
+    attached: Option<(Sid, WsWinsize)>,
+    /// Sequence number of the next chunk of output from the attached shell.
+    seq: u64,
+    /// Keystream offset for the next input, starting from a random value.
+    input_offset: u64,
+}
+
+impl Viewer {
+    async fn send(&mut self, msg: WsClient) -> Result<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&msg, &mut buf)?;
+        self.socket.send(Message::Binary(buf.into())).await?;
+        Ok(())
+    }
+
+    /// Receive the next message from the server, or `None` if it has closed.
+    async fn recv(&mut self) -> Result<Option<WsServer>> {
+        while let Some(msg) = self.socket.next().await {
+            match msg? {
+                Message::Binary(buf) => return Ok(Some(ciborium::de::from_reader(&*buf)?)),
+                Message::Close(Some(frame)) if frame.code == CloseCode::Library(4404) => {
+                    bail!("could not find the requested session");
+                }
+                Message::Close(Some(frame)) if frame.code != CloseCode::Normal => {
+                    bail!("connection closed by server: {}", frame.reason);
+                }
+                Message::Close(_) => return Ok(None),
+                _ => (),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Tell the server about the size of the local terminal.
+    async fn send_size(&mut self) -> Result<()> {
+        if let Some((id, winsize)) = self.attached {
+            if self.can_write {
+                let (rows, cols) = get_winsize()?;
+                let winsize = WsWinsize {
+                    rows,
+                    cols,
+                    ..winsize
+                };
+                self.send(WsClient::Move(id, Some(winsize))).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_input(&mut self, data: &[u8]) -> Result<()> {
+        if let Some((id, _)) = self.attached {
+            if self.can_write {
+                let offset = self.input_offset;
+                self.input_offset = offset.wrapping_add(data.len() as u64);
+                let encrypted = self.encrypt.segment(0x200000000, offset, data);
+                self.send(WsClient::Data(id, encrypted.into(), offset))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Attach to a shell in the session, displaying it in the local terminal.
+///
+/// Keystrokes are forwarded to the shell if the link grants write access.
+/// Returns when the user detaches with Ctrl-], or when the shell is closed.
+pub async fn attach(url: &SessionUrl, shell: Option<Sid>) -> Result<()> {
+    let endpoint = url.ws_endpoint();
+    let (socket, _) = tokio_tungstenite::connect_async(&endpoint)
+        .await
+        .with_context(|| format!("failed to connect to {endpoint}"))?;
+
+    let encrypt = Encrypt::new(&url.key);
+    let write_zeros = url
+        .write_password
+        .as_ref()
+        .map(|password| Encrypt::new(password).zeros().into());
+    let mut viewer = Viewer {
+        socket,
+        user_id: Uid(0),
+        can_write: false,
+        attached: None,
+        seq: 0,
+        input_offset: rand::random(),
+        encrypt,
+    };
+    viewer
+        .send(WsClient::Authenticate(
+            viewer.encrypt.zeros().into(),
+            write_zeros,
+        ))
+        .await?;
+    viewer.send(WsClient::SetName(whoami::username())).await?;
+
+    let mut waiting = false;
+    let mut raw_mode = None;
+    let mut stdin_rx: Option<mpsc::Receiver<Vec<u8>>> = None;
+    let mut sigwinch = signal(SignalKind::window_change())?;
+    let mut stdout = std_io::stdout();
+
+    loop {
+        tokio::select! {
+            msg = viewer.recv() => {
+                let Some(msg) = msg? else {
+                    break;
+                };
+                match msg {
+                    WsServer::Hello(user_id, name) => {
+                        debug!(%user_id, %name, "connected to session");
+                        viewer.user_id = user_id;
+                    }
+                    WsServer::InvalidAuth() => bail!("invalid encryption key or write password in link"),
+                    WsServer::WaitingApproval() => {
+                        print!("Waiting for the host to let you in...\r\n");
+                        stdout.flush()?;
+                    }
+                    WsServer::JoinDenied() => bail!("the host did not let you into the session"),
+                    WsServer::Kicked() => bail!("the host removed you from the session"),
+                    WsServer::Users(users) => {
+                        if let Some((_, user)) = users.iter().find(|(id, _)| *id == viewer.user_id) {
+                            viewer.can_write = user.can_write;
+                        }
+                    }
+                    WsServer::UserDiff(user_id, Some(user)) if user_id == viewer.user_id => {
+                        viewer.can_write = user.can_write;
+                    }
+                    WsServer::Shells(shells) => match viewer.attached {
+                        Some((id, _)) => match shells.iter().find(|(sid, _)| *sid == id) {
+                            Some(&(_, winsize)) => viewer.attached = Some((id, winsize)),
+                            None => break,
+                        },
+                        None => {
+                            let target = match shell {
+                                Some(id) => shells.iter().find(|(sid, _)| *sid == id),
+                                None => shells.first(),
+                            };
+                            let Some(&(id, winsize)) = target else {
+                                if let Some(id) = shell {
+                                    bail!("shell {id} does not exist in this session");
+                                }
+                                if !waiting {
+                                    // Nothing to display yet, so open a shell if we are allowed to.
+                                    waiting = true;
+                                    if viewer.can_write {
+                                        viewer.send(WsClient::Create(0, 0)).await?;
+                                    } else {
+                                        print!("Waiting for a shell to be opened...\r\n");
+                                        stdout.flush()?;
+                                    }
+                                }
+                                continue;
+                            };
+                            let mode = if viewer.can_write { "" } else { " (read-only)" };
+                            print!("Attached to shell {id}{mode}, press Ctrl-] to detach.\r\n");
+                            stdout.flush()?;
+                            raw_mode = Some(RawMode::enable()?);
+                            stdin_rx = Some(read_stdin());
+                            viewer.attached = Some((id, winsize));
+                            viewer.send(WsClient::Subscribe(id, 0)).await?;
+                            viewer.send_size().await?;
+                        }
+                    },
+                    WsServer::Chunks(id, seqnum, chunks) => {
+                        if viewer.attached.map(|(sid, _)| sid) != Some(id) {
+                            continue;
+                        }
+                        let data = new_output(&viewer.encrypt, id, &mut viewer.seq, seqnum, &chunks)?;
+                        stdout.write_all(&data)?;
+                        stdout.flush()?;
+                    }
+                    WsServer::Error(err) => warn!(%err, "error from server"),
+                    _ => (),
+                }
+            }
+            Some(data) = async { stdin_rx.as_mut()?.recv().await } => {
+                if let Some(index) = data.iter().position(|&b| b == DETACH_KEY) {
+                    viewer.send_input(&data[..index]).await?;
+                    break;
+                }
+                viewer.send_input(&data).await?;
+            }
+            Some(()) = sigwinch.recv() => viewer.send_size().await?,
+        }
+    }
+
+    drop(raw_mode);
+    println!();
+    viewer.socket.close(None).await.ok();
+    Ok(())
+}
+
+/// Decrypt chunks of output that start at sequence number `seqnum`, keeping
+/// only what comes after `seq` and advancing it.
+///
+/// Chunks that were already shown are skipped. Output before the first chunk
+/// may have been pruned by the server, so a later `seqnum` is jumped to.
+fn new_output(
+    encrypt: &Encrypt,
+    id: Sid,
+    seq: &mut u64,
+    seqnum: u64,
+    chunks: &[WsChunk],
+) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut start = seqnum;
+    for chunk in chunks {
+        let end = start + chunk.size();
+        if end > *seq {
+            let data = decode_chunk(encrypt, id, start, chunk)?;
+            let skip = seq.saturating_sub(start) as usize;
+            output.extend_from_slice(&data[skip..]);
+            *seq = end;
+        }
+        start = end;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use sshx_core::ws::WsChunk;
+    use sshx_core::Sid;
+
+    use super::{new_output, SessionUrl};
+    use crate::encrypt::Encrypt;
+
+    #[test]
+    fn parse_session_url() {
+        let url = SessionUrl::parse("https://sshx.io/s/abc123#secret").unwrap();
+        assert_eq!(url.origin, "https://sshx.io");
+        assert_eq!(url.name, "abc123");
+        assert_eq!(url.key, "secret");
+        assert_eq!(url.write_password, None);
+        assert_eq!(url.ws_endpoint(), "wss://sshx.io/api/s/abc123");
+
+        let url = SessionUrl::parse("http://localhost:8051/s/abc123#secret,write").unwrap();
+        assert_eq!(url.key, "secret");
+        assert_eq!(url.write_password.as_deref(), Some("write"));
+        assert_eq!(url.ws_endpoint(), "ws://localhost:8051/api/s/abc123");
+    }
+
+    #[test]
+    fn parse_invalid_session_url() {
+        assert!(SessionUrl::parse("https://sshx.io/s/abc123").is_err());
+        assert!(SessionUrl::parse("https://sshx.io/s/abc123#").is_err());
+        assert!(SessionUrl::parse("https://sshx.io/abc123#secret").is_err());
+        assert!(SessionUrl::parse("sshx.io/s/abc123#secret").is_err());
+    }
+
+    #[test]
+    fn attach_after_pruning() {
+        let encrypt = Encrypt::new("secret");
+        let chunk = |seq: u64, data: &str| {
+            let data = encrypt.segment(0x100000001, seq, data.as_bytes());
+            WsChunk::Raw(data.into())
+        };
+
+        // The server pruned everything before byte 3 MiB.
+        let mut seq = 0;
+        let start = 3 << 20;
+        let chunks = [chunk(start, "hello "), chunk(start + 6, "world")];
+        let output = new_output(&encrypt, Sid(1), &mut seq, start, &chunks).unwrap();
+        assert_eq!(output, b"hello world");
+        assert_eq!(seq, start + 11);
+
+        // Output that was already shown is skipped, even within a chunk.
+        let chunks = [chunk(start + 6, "world"), chunk(start + 11, "!")];
+        let output = new_output(&encrypt, Sid(1), &mut seq, start + 6, &chunks).unwrap();
+        assert_eq!(output, b"!");
+        let chunks = [chunk(start + 9, "ld!?")];
+        let output = new_output(&encrypt, Sid(1), &mut seq, start + 9, &chunks).unwrap();
+        assert_eq!(output, b"?");
+        assert_eq!(seq, start + 13);
+    }
+}
@@ -0,0 +1,19 @@
+/**
+ * @file Decompression of terminal output that was compressed before it was
+ * encrypted. Keep this file consistent with the Rust implementation.
+ */
+
+/** Decompress a raw deflate stream, which must expand to `size` bytes. */
+export async function inflate(
+  data: Uint8Array,
+  size: number,
+): Promise<Uint8Array> {
+  const stream = new Blob([data])
+    .stream()
+    .pipeThrough(new DecompressionStream("deflate-raw"));
+  const buf = new Uint8Array(await new Response(stream).arrayBuffer());
+  if (buf.length !== size) {
+    throw new Error("compressed terminal data has the wrong length");
+  }
+  return buf;
+}
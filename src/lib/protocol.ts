@@ -17,14 +17,34 @@ export type WsUser = {
   canWrite: boolean;
 };
 
+/** How the process in a shell ended, see the Rust version. */
+export type WsExitStatus = { code: number } | { signal: number };
+
+/** Details about what is running in a shell, see the Rust version. */
+export type ShellInfo = {
+  title: string | null;
+  cwd: string | null;
+  process: string | null;
+};
+
+/** Chunk of encrypted terminal output, see the Rust version. */
+export type WsChunk =
+  | Uint8Array
+  | { data: Uint8Array; size: number; offset: number | bigint };
+
 /** Server message type, see the Rust version. */
 export type WsServer = {
   hello?: [Uid, string];
   invalidAuth?: [];
+  waitingApproval?: [];
+  joinDenied?: [];
+  kicked?: [];
   users?: [Uid, WsUser][];
   userDiff?: [Uid, WsUser | null];
   shells?: [Sid, WsWinsize][];
-  chunks?: [Sid, number, Uint8Array[]];
+  shellExited?: [Sid, WsExitStatus];
+  shellInfo?: [Sid, number | bigint, Uint8Array];
+  chunks?: [Sid, number, WsChunk[]];
   hear?: [Uid, string, string];
   shellLatency?: number | bigint;
   pong?: number | bigint;